@@ -0,0 +1,9 @@
+-- newsletter subscribers; status is 'pending_confirmation' until the confirmation link is followed
+CREATE TABLE subscriptions(
+    id uuid NOT NULL,
+    email TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    subscribed_at timestamptz NOT NULL,
+    status TEXT NOT NULL,
+    PRIMARY KEY(id)
+);
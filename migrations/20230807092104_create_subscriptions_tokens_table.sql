@@ -0,0 +1,7 @@
+-- confirmation tokens sent to pending subscribers
+CREATE TABLE subscriptions_tokens(
+    subscription_token TEXT NOT NULL,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    PRIMARY KEY(subscription_token)
+);
@@ -0,0 +1,6 @@
+-- admins allowed to publish issues; passwords are stored as Argon2id PHC strings
+CREATE TABLE users(
+    user_id uuid PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL
+);
@@ -0,0 +1,7 @@
+-- server-side state of admin sessions, the cookie only carries the session key
+CREATE TABLE sessions(
+    session_key TEXT PRIMARY KEY,
+    state JSONB NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+CREATE INDEX sessions_expires_at_idx ON sessions (expires_at);
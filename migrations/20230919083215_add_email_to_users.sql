@@ -0,0 +1,2 @@
+-- password reset links are sent to this address; admins without one cannot reset their password
+ALTER TABLE users ADD COLUMN email TEXT UNIQUE;
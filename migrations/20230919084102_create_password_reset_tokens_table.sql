@@ -0,0 +1,7 @@
+-- only the SHA-256 of a reset token is stored, the token itself lives in the email
+CREATE TABLE password_reset_tokens(
+    token_hash TEXT PRIMARY KEY,
+    user_id uuid NOT NULL
+        REFERENCES users (user_id),
+    expires_at timestamptz NOT NULL
+);
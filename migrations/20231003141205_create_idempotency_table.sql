@@ -0,0 +1,17 @@
+-- responses of requests carrying an idempotency key, replayed to retries of the same key
+CREATE TYPE header_pair AS (
+    name TEXT,
+    value BYTEA
+);
+-- the response columns are filled in by the same transaction that inserts the row,
+-- so other sessions never see them empty
+CREATE TABLE idempotency(
+    user_id uuid NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    response_status_code SMALLINT,
+    response_headers header_pair[],
+    response_body BYTEA,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY(user_id, idempotency_key)
+);
+CREATE INDEX idempotency_created_at_idx ON idempotency (created_at);
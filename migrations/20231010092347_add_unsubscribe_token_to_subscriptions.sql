@@ -0,0 +1,7 @@
+-- every subscriber gets a stable token for the List-Unsubscribe link of each newsletter
+ALTER TABLE subscriptions ADD COLUMN unsubscribe_token TEXT NULL;
+UPDATE subscriptions
+    SET unsubscribe_token = replace(gen_random_uuid()::text, '-', '')
+    WHERE unsubscribe_token IS NULL;
+ALTER TABLE subscriptions ALTER COLUMN unsubscribe_token SET NOT NULL;
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_unsubscribe_token_key UNIQUE (unsubscribe_token);
@@ -0,0 +1,16 @@
+-- transactional emails written in the same transaction as the change that triggers them,
+-- delivered afterwards by the outbox dispatcher
+CREATE TABLE email_outbox(
+    email_id uuid NOT NULL PRIMARY KEY,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    n_retries SMALLINT NOT NULL DEFAULT 0,
+    execute_after timestamptz NOT NULL DEFAULT now(),
+    -- set once the email ran out of attempts; the row is kept for inspection and never retried
+    failed_at timestamptz NULL,
+    last_error TEXT NULL
+);
+CREATE INDEX email_outbox_pending_idx ON email_outbox (execute_after) WHERE failed_at IS NULL;
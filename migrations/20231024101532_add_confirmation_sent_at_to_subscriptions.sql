@@ -0,0 +1,2 @@
+-- remembers when the last confirmation email was queued, so re-submitting the form cannot flood an inbox
+ALTER TABLE subscriptions ADD COLUMN confirmation_sent_at timestamptz NULL;
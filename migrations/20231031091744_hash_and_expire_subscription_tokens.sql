@@ -0,0 +1,8 @@
+-- only the SHA-256 of a confirmation token is stored, the token itself lives in the email
+ALTER TABLE subscriptions_tokens RENAME COLUMN subscription_token TO token_hash;
+UPDATE subscriptions_tokens SET token_hash = encode(sha256(convert_to(token_hash, 'UTF8')), 'hex');
+ALTER TABLE subscriptions_tokens ADD COLUMN issued_at timestamptz NOT NULL DEFAULT now();
+-- links that were already sent get the default lifetime from now on
+ALTER TABLE subscriptions_tokens ADD COLUMN expires_at timestamptz NOT NULL DEFAULT now() + interval '72 hours';
+ALTER TABLE subscriptions_tokens ALTER COLUMN issued_at DROP DEFAULT;
+ALTER TABLE subscriptions_tokens ALTER COLUMN expires_at DROP DEFAULT;
@@ -0,0 +1,3 @@
+-- fingerprint of the request a key was first used for, a key sent again with a different request is refused;
+-- rows saved before this migration have none and are replayed as before
+ALTER TABLE idempotency ADD COLUMN request_hash TEXT NULL;
@@ -0,0 +1,8 @@
+mod middleware;
+mod password;
+
+pub use middleware::{reject_anonymous_users, UserId};
+pub use password::{
+    authenticate_basic, basic_authentication, change_password, hash_password,
+    validate_credentials, validate_new_password, AuthError, Credentials,
+};
@@ -0,0 +1,278 @@
+use crate::telemetry::spawn_blocking_with_tracing;
+use crate::utils::error_chain_fmt;
+use actix_web::http::header::HeaderMap;
+use actix_web::HttpRequest;
+use anyhow::Context;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use base64::Engine;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+//PHC string of a random password; verified against when the username does not exist
+//so that unknown and known usernames take the same time to reject
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=15000,t=2,p=1$\
+gZiV/M1gPc22ElAH/Jh1Hw$\
+CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno";
+
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum AuthError {
+    #[error("Invalid credentials.")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let mut user_id = None;
+    let mut expected_password_hash = Secret::new(DUMMY_PASSWORD_HASH.to_string());
+
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool).await?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    //hashing is cpu-bound and takes tens of milliseconds, keep it off the actix workers
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .context("Failed to spawn blocking task.")??;
+
+    //only reached when the password matched the dummy hash of an unknown user
+    user_id.ok_or_else(|| AuthError::InvalidCredentials(anyhow::anyhow!("Unknown username.")))
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password_candidate: Secret<String>,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .context("Failed to parse hash in PHC string format.")?;
+    //parameters are read from the PHC string, so old hashes keep verifying if we tune them
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash,
+        )
+        .context("Invalid password.")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(Uuid, Secret<String>)>, AuthError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT user_id, password_hash
+        FROM users
+        WHERE username = $1
+        "#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve stored credentials.")?
+    .map(|row| (row.user_id, Secret::new(row.password_hash)));
+    Ok(row)
+}
+
+#[tracing::instrument(name = "Change password", skip(password, pool))]
+pub async fn change_password(
+    user_id: Uuid,
+    password: Secret<String>,
+    pool: &PgPool,
+) -> Result<(), AuthError> {
+    let password_hash = hash_password(password).await?;
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET password_hash = $1
+        WHERE user_id = $2
+        "#,
+        password_hash.expose_secret(),
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to change user's password in the database.")?;
+    Ok(())
+}
+
+//hash a new password into a PHC string, off the actix workers like verification
+pub async fn hash_password(password: Secret<String>) -> Result<Secret<String>, AuthError> {
+    spawn_blocking_with_tracing(move || compute_password_hash(password))
+        .await
+        .context("Failed to spawn blocking task.")?
+}
+
+fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, AuthError> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    //same parameters as the dummy hash, so both take the same time to verify
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).context("Invalid Argon2 parameters.")?,
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)
+    .context("Failed to hash password.")?
+    .to_string();
+    Ok(Secret::new(password_hash))
+}
+
+//rules every new password has to follow; the error is meant to be shown to the user
+pub fn validate_new_password(
+    new_password: &Secret<String>,
+    new_password_check: &Secret<String>,
+) -> Result<(), String> {
+    if new_password.expose_secret() != new_password_check.expose_secret() {
+        return Err("You entered two different new passwords - the field values must match.".into());
+    }
+    let length = new_password.expose_secret().chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(format!(
+            "The new password must be between {} and {} characters long.",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+//extract the username and password of an `Authorization: Basic ...` header
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, String> {
+    let header_value = headers
+        .get("Authorization")
+        .ok_or("The 'Authorization' header was missing")?
+        .to_str()
+        .map_err(|_| "The 'Authorization' header was not a valid UTF8 string")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .ok_or("The authorization scheme was not 'Basic'")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64encoded_segment)
+        .map_err(|_| "Failed to base64-decode 'Basic' credentials")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .map_err(|_| "The decoded credential string is not valid UTF8")?;
+
+    //split into two segments, using ':' as delimiter
+    let (username, password) = decoded_credentials
+        .split_once(':')
+        .ok_or("A password must be provided in 'Basic' auth")?;
+
+    Ok(Credentials {
+        username: username.to_string(),
+        password: Secret::new(password.to_string()),
+    })
+}
+
+//checks the basic auth credentials of a request, recording who made it on the current span
+pub async fn authenticate_basic(request: &HttpRequest, pool: &PgPool) -> Result<Uuid, AuthError> {
+    let credentials = basic_authentication(request.headers())
+        .map_err(|e| AuthError::InvalidCredentials(anyhow::anyhow!(e)))?;
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+    let user_id = validate_credentials(credentials, pool).await?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{basic_authentication, validate_new_password, DUMMY_PASSWORD_HASH};
+    use actix_web::http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+    use argon2::PasswordHash;
+    use claims::assert_ok;
+    use secrecy::{ExposeSecret, Secret};
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        headers
+    }
+
+    #[test]
+    fn the_dummy_hash_is_a_valid_phc_string() {
+        assert_ok!(PasswordHash::new(DUMMY_PASSWORD_HASH));
+    }
+
+    #[test]
+    fn basic_credentials_are_decoded() {
+        //"ursula:le guin" in base64
+        let credentials = basic_authentication(&headers("Basic dXJzdWxhOmxlIGd1aW4=")).unwrap();
+        assert_eq!(credentials.username, "ursula");
+        assert_eq!(credentials.password.expose_secret(), "le guin");
+    }
+
+    #[test]
+    fn a_missing_authorization_header_is_rejected() {
+        assert!(basic_authentication(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn other_authorization_schemes_are_rejected() {
+        assert!(basic_authentication(&headers("Bearer dXJzdWxhOmxlIGd1aW4=")).is_err());
+    }
+
+    #[test]
+    fn credentials_without_a_password_are_rejected() {
+        //"ursula" in base64
+        assert!(basic_authentication(&headers("Basic dXJzdWxh")).is_err());
+    }
+
+    fn secret(s: &str) -> Secret<String> {
+        Secret::new(s.to_string())
+    }
+
+    #[test]
+    fn new_passwords_must_match() {
+        let password = "a-long-enough-password";
+        assert!(validate_new_password(&secret(password), &secret("another-long-password")).is_err());
+        assert_ok!(validate_new_password(&secret(password), &secret(password)));
+    }
+
+    #[test]
+    fn a_password_shorter_than_12_characters_is_rejected() {
+        let password = "a".repeat(11);
+        assert!(validate_new_password(&secret(&password), &secret(&password)).is_err());
+    }
+
+    #[test]
+    fn a_password_longer_than_128_characters_is_rejected() {
+        let password = "a".repeat(129);
+        assert!(validate_new_password(&secret(&password), &secret(&password)).is_err());
+    }
+
+    #[test]
+    fn passwords_at_the_length_bounds_are_accepted() {
+        for length in [12, 128] {
+            let password = "ë".repeat(length);
+            assert_ok!(validate_new_password(&secret(&password), &secret(&password)));
+        }
+    }
+}
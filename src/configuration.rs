@@ -1,38 +1,310 @@
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    EmailSender, FileSink, MailgunClient, PostmarkClient, SendGridClient, SesClient, SmtpClient,
+    StdoutSink,
+};
+use crate::idempotency::IdempotencyRetention;
+use crate::routes::{ConfirmationResendInterval, SubscriptionTokenTtl};
+use crate::telemetry::RedactionPolicy;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
 use sqlx::postgres::PgConnectOptions;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    #[serde(default)]
+    pub telemetry: TelemetrySettings,
 }
 
 #[derive(Deserialize,Clone)]
 pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
-    pub base_url: String
+    pub base_url: String,
+    pub hmac_secret: Secret<String>,
+    pub idempotency_retention_hours: i64,
+    pub confirmation_resend_interval_seconds: i64,
+    pub subscription_token_ttl_hours: i64,
+    pub readiness_timeout_milliseconds: u64,
+    pub readiness_probe_email_provider: bool,
+    pub shutdown_grace_period_seconds: u64,
+}
+
+impl ApplicationSettings {
+    pub fn idempotency_retention(&self) -> IdempotencyRetention {
+        IdempotencyRetention(chrono::Duration::hours(self.idempotency_retention_hours))
+    }
+
+    pub fn confirmation_resend_interval(&self) -> ConfirmationResendInterval {
+        ConfirmationResendInterval(chrono::Duration::seconds(self.confirmation_resend_interval_seconds))
+    }
+
+    pub fn subscription_token_ttl(&self) -> SubscriptionTokenTtl {
+        SubscriptionTokenTtl(chrono::Duration::hours(self.subscription_token_ttl_hours))
+    }
+
+    pub fn readiness_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.readiness_timeout_milliseconds)
+    }
+
+    pub fn shutdown_grace_period(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown_grace_period_seconds)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailProvider {
+    #[default]
+    Postmark,
+    SendGrid,
+    Mailgun,
+    Ses,
+    Smtp,
+    //development sinks, nothing is actually sent
+    File,
+    Stdout,
+}
+impl EmailProvider {
+    //the `provider` label of the email metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailProvider::Postmark => "postmark",
+            EmailProvider::SendGrid => "sendgrid",
+            EmailProvider::Mailgun => "mailgun",
+            EmailProvider::Ses => "ses",
+            EmailProvider::Smtp => "smtp",
+            EmailProvider::File => "file",
+            EmailProvider::Stdout => "stdout",
+        }
+    }
 }
 
 #[derive(Deserialize,Clone)]
 pub struct EmailClientSettings {
+    #[serde(default)]
+    pub provider: EmailProvider,
     pub base_url: String,
     pub sender_email: String,
+    //server token, api key, SES secret access key or SMTP password depending on the provider
     pub authorization_token: Secret<String>,
-    pub timeout_milliseconds: u64
+    pub timeout_milliseconds: u64,
+    pub max_attempts: i16,
+    pub mailgun_domain: Option<String>,
+    pub ses_region: Option<String>,
+    pub ses_access_key_id: Option<String>,
+    pub smtp: Option<SmtpSettings>,
+    pub file: Option<FileSinkSettings>,
 }
 impl EmailClientSettings {
+    //both the api and the delivery worker need their own client built from the same settings
+    pub fn client(self) -> Result<Arc<dyn EmailSender>, String> {
+        let sender = self.sender()?;
+        let timeout = self.timeout();
+        let client: Arc<dyn EmailSender> = match self.provider {
+            EmailProvider::Postmark => Arc::new(PostmarkClient::new(
+                self.base_url,
+                sender,
+                self.authorization_token,
+                timeout,
+            )),
+            EmailProvider::SendGrid => Arc::new(SendGridClient::new(
+                self.base_url,
+                sender,
+                self.authorization_token,
+                timeout,
+            )),
+            EmailProvider::Mailgun => Arc::new(MailgunClient::new(
+                self.base_url,
+                required(self.mailgun_domain, "mailgun_domain")?,
+                sender,
+                self.authorization_token,
+                timeout,
+            )),
+            EmailProvider::Ses => Arc::new(SesClient::new(
+                self.base_url,
+                required(self.ses_region, "ses_region")?,
+                sender,
+                required(self.ses_access_key_id, "ses_access_key_id")?,
+                self.authorization_token,
+                timeout,
+            )),
+            EmailProvider::Smtp => Arc::new(SmtpClient::new(
+                required(self.smtp, "smtp")?.transport(self.authorization_token, timeout)?,
+                sender,
+            )),
+            EmailProvider::File => {
+                let file = required(self.file, "file")?;
+                let directory = std::path::PathBuf::from(file.directory);
+                match file.format {
+                    FileSinkFormat::Eml => Arc::new(FileSink::eml(directory, sender)),
+                    FileSinkFormat::Maildir => Arc::new(FileSink::maildir(directory, sender)),
+                }
+            }
+            EmailProvider::Stdout => Arc::new(StdoutSink::new(sender)),
+        };
+        Ok(client)
+    }
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
-        SubscriberEmail::parse(self.sender_email.clone())
+        SubscriberEmail::parse(self.sender_email.clone()).map_err(|e| e.to_string())
     }
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
 }
 
+fn required<T>(value: Option<T>, field: &str) -> Result<T, String> {
+    value.ok_or_else(|| format!("`email_client.{}` is required for the selected provider", field))
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSinkFormat {
+    #[default]
+    Eml,
+    Maildir,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FileSinkSettings {
+    //relative paths are resolved against the working directory
+    pub directory: String,
+    #[serde(default)]
+    pub format: FileSinkFormat,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    //plaintext, only for a relay on localhost or a trusted private network
+    None,
+    //plaintext connection upgraded with STARTTLS, refusing relays that do not offer it
+    #[default]
+    StartTls,
+    //TLS from the first byte, usually on port 465
+    Tls,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpAuthMechanism {
+    Plain,
+    Login,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct TelemetrySettings {
+    #[serde(default)]
+    pub redaction: RedactionPolicy,
+    //keys the digests of the hash policy, which refuses to start without one
+    pub redaction_key: Option<Secret<String>>,
+    //spans only leave the process when a collector is configured
+    pub otlp: Option<OtlpSettings>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OtlpSettings {
+    //base url of the collector, e.g. http://localhost:4317 for grpc or http://localhost:4318 for http
+    pub endpoint: String,
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+    pub timeout_milliseconds: Option<u64>,
+}
+
+impl OtlpSettings {
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_milliseconds.unwrap_or(10000))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    #[default]
+    Grpc,
+    //protobuf over http
+    Http,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    //defaults to the usual port of the tls mode
+    pub port: Option<u16>,
+    //authentication is skipped without a username, the password is `authorization_token`
+    pub username: Option<String>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    //tried in order, defaults to PLAIN then LOGIN
+    pub auth_mechanisms: Option<Vec<SmtpAuthMechanism>>,
+    pub pool_max_size: Option<u32>,
+    pub pool_idle_timeout_seconds: Option<u64>,
+}
+
+impl SmtpSettings {
+    pub fn transport(
+        &self,
+        password: Secret<String>,
+        timeout: std::time::Duration,
+    ) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+        let tls = match self.tls {
+            SmtpTls::None => Tls::None,
+            SmtpTls::StartTls => Tls::Required(self.tls_parameters()?),
+            SmtpTls::Tls => Tls::Wrapper(self.tls_parameters()?),
+        };
+        let port = self.port.unwrap_or(match self.tls {
+            SmtpTls::None => 25,
+            SmtpTls::StartTls => 587,
+            SmtpTls::Tls => 465,
+        });
+        let mut pool = PoolConfig::new();
+        if let Some(max_size) = self.pool_max_size {
+            pool = pool.max_size(max_size);
+        }
+        if let Some(idle_timeout) = self.pool_idle_timeout_seconds {
+            pool = pool.idle_timeout(std::time::Duration::from_secs(idle_timeout));
+        }
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+            .port(port)
+            .tls(tls)
+            .timeout(Some(timeout))
+            .pool_config(pool);
+        if let Some(username) = &self.username {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                password.expose_secret().to_string(),
+            ));
+        }
+        if let Some(mechanisms) = &self.auth_mechanisms {
+            builder = builder.authentication(
+                mechanisms
+                    .iter()
+                    .map(|mechanism| match mechanism {
+                        SmtpAuthMechanism::Plain => Mechanism::Plain,
+                        SmtpAuthMechanism::Login => Mechanism::Login,
+                    })
+                    .collect(),
+            );
+        }
+        Ok(builder.build())
+    }
+
+    fn tls_parameters(&self) -> Result<TlsParameters, String> {
+        TlsParameters::new(self.host.clone())
+            .map_err(|e| format!("Invalid TLS settings for {}: {}", self.host, e))
+    }
+}
+
 #[derive(Deserialize,Clone)]
 pub struct DatabaseSettings {
     pub username: String,
@@ -83,9 +355,16 @@ impl TryFrom<String> for Environment {
 }
 
 //we want to read the settings from a yaml file and convert it to a a rust type that we have defined above.
+//every key can be overridden from the environment, e.g. APP_DATABASE__PASSWORD for `database.password`,
+//or APP_DATABASE__PASSWORD_FILE=/run/secrets/db_password to read it from a mounted secret
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
-    let base_path = std::env::current_dir().expect("Failed to determine the current directory");
-    let configuration_directory = base_path.join("configuration");
+    //APP_CONFIGURATION_DIRECTORY moves the yaml files out of the working directory, e.g. to a mounted volume
+    let configuration_directory = match std::env::var("APP_CONFIGURATION_DIRECTORY") {
+        Ok(directory) => PathBuf::from(directory),
+        Err(_) => std::env::current_dir()
+            .expect("Failed to determine the current directory")
+            .join("configuration"),
+    };
 
     //detect the running env
     //default to local if unspecified
@@ -93,16 +372,142 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         .unwrap_or_else(|_| "local".into())
         .try_into()
         .expect("Failed to parse APP_ENVIRONMENT");
+    load_configuration(&configuration_directory, environment, std::env::vars())
+}
+
+//later sources win: base.yaml, then the environment's file, then APP_ variables, then secret files
+fn load_configuration(
+    configuration_directory: &Path,
+    environment: Environment,
+    variables: impl IntoIterator<Item = (String, String)>,
+) -> Result<Settings, config::ConfigError> {
+    let variables: HashMap<String, String> = variables
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
     let environment_filename = format!("{}.yaml", environment.as_str());
     //initialize the configuration reader
-    let settings = config::Config::builder()
+    let mut builder = config::Config::builder()
         .add_source(config::File::from(
             configuration_directory.join("base.yaml"),
         ))
         .add_source(config::File::from(
             configuration_directory.join(environment_filename),
         ))
-        .build()?;
+        //values stay strings, so a numeric password or token is not turned into a number
+        .add_source(
+            config::Environment::with_prefix("APP")
+                .prefix_separator("_")
+                .separator("__")
+                .source(Some(variables.clone())),
+        );
+    for (key, path) in secret_files(&variables)? {
+        let secret = std::fs::read_to_string(&path).map_err(|e| {
+            config::ConfigError::Message(format!("Failed to read the secret for `{}` from {}: {}", key, path, e))
+        })?;
+        //editors and `echo` leave a trailing newline that is not part of the secret
+        builder = builder.set_override(key, secret.trim_end_matches(['\r', '\n']))?;
+    }
     //try to convert the configuration values it reads into our settings type
-    settings.try_deserialize::<Settings>()
+    builder.build()?.try_deserialize::<Settings>()
+}
+
+const ENV_PREFIX: &str = "APP_";
+const SECRET_FILE_SUFFIX: &str = "_FILE";
+
+//APP_EMAIL_CLIENT__AUTHORIZATION_TOKEN_FILE=path becomes (`email_client.authorization_token`, path)
+fn secret_files(variables: &HashMap<String, String>) -> Result<Vec<(String, String)>, config::ConfigError> {
+    let mut secret_files = Vec::new();
+    for (name, path) in variables {
+        let variable = match name.strip_suffix(SECRET_FILE_SUFFIX) {
+            //a `__FILE` suffix would be a nested key called `file`, not a secret file
+            Some(variable) if !variable.ends_with('_') => variable,
+            _ => continue,
+        };
+        if variables.contains_key(variable) {
+            return Err(config::ConfigError::Message(format!(
+                "Both {} and {} are set, only one of them can be used",
+                variable, name
+            )));
+        }
+        let key = variable[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+        secret_files.push((key, path.clone()));
+    }
+    Ok(secret_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_configuration, Environment, Settings};
+    use claims::assert_ok;
+    use secrecy::ExposeSecret;
+    use std::path::{Path, PathBuf};
+    use uuid::Uuid;
+
+    fn configuration_directory() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("configuration")
+    }
+
+    fn load(variables: &[(&str, &str)]) -> Result<Settings, config::ConfigError> {
+        let variables = [("APP_DATABASE__HOST", "localhost")]
+            .iter()
+            .chain(variables)
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        load_configuration(&configuration_directory(), Environment::Local, variables)
+    }
+
+    fn write_secret(content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("z2p-secret-{}", Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn nested_keys_are_overridden_with_a_double_underscore_separator() {
+        let settings = assert_ok!(load(&[
+            ("APP_APPLICATION__PORT", "9000"),
+            ("APP_EMAIL_CLIENT__AUTHORIZATION_TOKEN", "from-the-environment"),
+        ]));
+
+        assert_eq!(settings.application.port, 9000);
+        assert_eq!(
+            settings.email_client.authorization_token.expose_secret(),
+            "from-the-environment"
+        );
+    }
+
+    #[test]
+    fn variables_without_the_prefix_are_ignored() {
+        let settings = assert_ok!(load(&[("DATABASE__PORT", "1234")]));
+
+        assert_eq!(settings.database.port, 5432);
+    }
+
+    #[test]
+    fn secrets_are_read_from_the_file_named_by_a_file_variable() {
+        let path = write_secret("s3cr3t-password\n");
+
+        let settings = assert_ok!(load(&[("APP_DATABASE__PASSWORD_FILE", path.to_str().unwrap())]));
+
+        assert_eq!(settings.database.password.expose_secret(), "s3cr3t-password");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_missing_secret_file_is_an_error() {
+        assert!(load(&[("APP_DATABASE__PASSWORD_FILE", "/definitely/not/a/secret")]).is_err());
+    }
+
+    #[test]
+    fn a_key_cannot_be_set_both_directly_and_from_a_file() {
+        let path = write_secret("s3cr3t-password");
+
+        let settings = load(&[
+            ("APP_DATABASE__PASSWORD", "password"),
+            ("APP_DATABASE__PASSWORD_FILE", path.to_str().unwrap()),
+        ]);
+
+        assert!(settings.is_err());
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -1,5 +1,5 @@
-use crate::domain::subscriber_email::SubscriberEmail;
-use crate::domain::subscriber_name::SubscriberName;
+use crate::domain::subscriber_email::{SubscriberEmail, SubscriberEmailError};
+use crate::domain::subscriber_name::{SubscriberName, SubscriberNameError};
 use crate::routes::FormData;
 
 #[derive(Debug)]
@@ -7,11 +7,85 @@ pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
 }
+
+//every rule of every field is checked, so a form can point out all of its mistakes at once
+#[derive(Debug, thiserror::Error)]
+#[error("{}", self.violations().iter().map(|v| v.message.as_str()).collect::<Vec<_>>().join(" "))]
+pub struct NewSubscriberError {
+    //empty when the name is valid
+    pub name: Vec<SubscriberNameError>,
+    pub email: Option<SubscriberEmailError>,
+}
+
+pub struct FieldViolation {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl NewSubscriberError {
+    pub fn violations(&self) -> Vec<FieldViolation> {
+        let name = self.name.iter().map(|e| FieldViolation {
+            field: "name",
+            code: e.code(),
+            message: e.to_string(),
+        });
+        let email = self.email.iter().map(|e| FieldViolation {
+            field: "email",
+            code: e.code(),
+            message: e.to_string(),
+        });
+        name.chain(email).collect()
+    }
+}
+
 impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
-    fn try_from(value: FormData) -> Result<NewSubscriber, String> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(NewSubscriber { email, name })
+    type Error = NewSubscriberError;
+    fn try_from(value: FormData) -> Result<NewSubscriber, NewSubscriberError> {
+        match (SubscriberName::parse(value.name), SubscriberEmail::parse(value.email)) {
+            (Ok(name), Ok(email)) => Ok(NewSubscriber { email, name }),
+            (name, email) => Err(NewSubscriberError {
+                name: name.err().unwrap_or_default(),
+                email: email.err(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::assert_err;
+
+    fn form(name: &str, email: &str) -> FormData {
+        FormData {
+            name: name.into(),
+            email: email.into(),
+            idempotency_key: None,
+        }
+    }
+
+    #[test]
+    fn both_invalid_fields_are_reported() {
+        let error = assert_err!(NewSubscriber::try_from(form("<ursula>", "not-an-email")));
+        let codes: Vec<_> = error.violations().iter().map(|v| v.code).collect();
+        assert_eq!(codes, vec!["name.forbidden_characters", "email.invalid"]);
+    }
+
+    #[test]
+    fn every_rule_broken_by_a_field_is_reported() {
+        let name = format!("<{}", "a".repeat(256));
+        let error = assert_err!(NewSubscriber::try_from(form(&name, "ursula@example.com")));
+        let codes: Vec<_> = error.violations().iter().map(|v| v.code).collect();
+        assert_eq!(codes, vec!["name.too_long", "name.forbidden_characters"]);
+    }
+
+    #[test]
+    fn a_single_invalid_field_is_reported_alone() {
+        let error = assert_err!(NewSubscriber::try_from(form("", "ursula@example.com")));
+        let violations = error.violations();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].field, "name");
+        assert_eq!(violations[0].code, "name.empty");
     }
 }
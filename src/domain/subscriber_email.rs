@@ -1,14 +1,26 @@
+use crate::telemetry::redact_email;
+use std::fmt;
 use validator::validate_email;
 
-#[derive(Debug)]
 pub struct SubscriberEmail(String);
 
+//the rejected input is kept out of the message, error messages end up in the logs
+#[derive(thiserror::Error)]
+#[error("The subscriber email is not a valid email address.")]
+pub struct SubscriberEmailError(String);
+
+impl SubscriberEmailError {
+    pub fn code(&self) -> &'static str {
+        "email.invalid"
+    }
+}
+
 impl SubscriberEmail {
-    pub fn parse(s: String) -> Result<SubscriberEmail, String> {
+    pub fn parse(s: String) -> Result<SubscriberEmail, SubscriberEmailError> {
         if validate_email(&s) {
             Ok(Self(s))
         } else {
-            Err(format!("{} is not a valid subscriber email", s))
+            Err(SubscriberEmailError(s))
         }
     }
 }
@@ -18,6 +30,19 @@ impl AsRef<str> for SubscriberEmail {
     }
 }
 
+//redacted, so a `{:?}` in an error or a span does not leak the address
+impl fmt::Debug for SubscriberEmail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SubscriberEmail").field(&redact_email(&self.0)).finish()
+    }
+}
+
+impl fmt::Debug for SubscriberEmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SubscriberEmailError").field(&redact_email(&self.0)).finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -46,6 +71,12 @@ mod tests {
         assert_err!(SubscriberEmail::parse(email));
     }
 
+    #[test]
+    fn debug_output_does_not_reveal_the_address() {
+        let email = SubscriberEmail::parse("ursula_le_guin@gmail.com".to_string()).unwrap();
+        assert_eq!(format!("{:?}", email), r#"SubscriberEmail("u***@gmail.com")"#);
+    }
+
     #[derive(Debug, Clone)]
     struct ValidEmailFixture(pub String);
     //updated code
@@ -1,24 +1,52 @@
+use crate::telemetry::redact_name;
+use std::fmt;
 use unicode_segmentation::UnicodeSegmentation;
 
 // this struct will represent our subscriber name
-#[derive(Debug)]
 pub struct SubscriberName(String);
 
+#[derive(thiserror::Error, PartialEq)]
+pub enum SubscriberNameError {
+    #[error("The subscriber name cannot be empty.")]
+    Empty,
+    #[error("The subscriber name cannot be longer than 256 characters.")]
+    TooLong,
+    #[error("The subscriber name contains characters that are not allowed.")]
+    ForbiddenCharacters(String),
+}
+
+impl SubscriberNameError {
+    //stable identifier clients can match on, unlike the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            SubscriberNameError::Empty => "name.empty",
+            SubscriberNameError::TooLong => "name.too_long",
+            SubscriberNameError::ForbiddenCharacters(_) => "name.forbidden_characters",
+        }
+    }
+}
+
 impl SubscriberName {
-    /// Returns an instance of `SubscriberName if the input satisfies all our
-    /// validation constraints on subscriber names. It panics otherwise
-    pub fn parse(s: String) -> Result<SubscriberName, String> {
-        let is_empty_or_whitespace = s.trim().is_empty();
+    /// Returns an instance of `SubscriberName` if the input satisfies all our
+    /// validation constraints on subscriber names, every violated one otherwise
+    pub fn parse(s: String) -> Result<SubscriberName, Vec<SubscriberNameError>> {
+        //an empty name cannot break any other rule
+        if s.trim().is_empty() {
+            return Err(vec![SubscriberNameError::Empty]);
+        }
+        let mut errors = Vec::new();
         //count all characters in name including chars in graphemes set
-        let is_too_long = s.graphemes(true).count() > 256;
+        if s.graphemes(true).count() > 256 {
+            errors.push(SubscriberNameError::TooLong);
+        }
         let forbidden_characters = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let contains_forbidden_characters = s.chars().any(|c| forbidden_characters.contains(&c));
-        //return false if any of the conditions were violated
-        if is_too_long || is_empty_or_whitespace || contains_forbidden_characters {
-            // panic!("{} is not a valid subscriber name.", s);
-            Err(format!("{} is not a valid subscriber name.", s))
-        } else {
+        if s.chars().any(|c| forbidden_characters.contains(&c)) {
+            errors.push(SubscriberNameError::ForbiddenCharacters(s.clone()));
+        }
+        if errors.is_empty() {
             Ok(Self(s))
+        } else {
+            Err(errors)
         }
     }
 }
@@ -28,9 +56,29 @@ impl AsRef<str> for SubscriberName {
     }
 }
 
+//redacted, so a `{:?}` in an error or a span does not leak the name
+impl fmt::Debug for SubscriberName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SubscriberName").field(&redact_name(&self.0)).finish()
+    }
+}
+
+impl fmt::Debug for SubscriberNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriberNameError::Empty => write!(f, "Empty"),
+            SubscriberNameError::TooLong => write!(f, "TooLong"),
+            SubscriberNameError::ForbiddenCharacters(name) => f
+                .debug_tuple("ForbiddenCharacters")
+                .field(&redact_name(name))
+                .finish(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::domain::subscriber_name::SubscriberName;
+    use crate::domain::subscriber_name::{SubscriberName, SubscriberNameError};
     use claims::{assert_err, assert_ok};
 
     #[test]
@@ -42,13 +90,13 @@ mod tests {
     #[test]
     fn a_name_longer_than_256_chars_is_rejected() {
         let name = "a".repeat(257);
-        assert_err!(SubscriberName::parse(name));
+        assert_eq!(assert_err!(SubscriberName::parse(name)), vec![SubscriberNameError::TooLong]);
     }
 
     #[test]
     fn whitespace_only_names_are_rejected() {
         let name = " ".to_string();
-        assert_err!(SubscriberName::parse(name));
+        assert_eq!(assert_err!(SubscriberName::parse(name)), vec![SubscriberNameError::Empty]);
     }
 
     #[test]
@@ -61,10 +109,31 @@ mod tests {
     fn name_containing_invalid_characters_are_rejected() {
         for name in &['/', '(', ')', '"', '<', '>', '\\', '{', '}'] {
             let name = name.to_string();
-            assert_err!(SubscriberName::parse(name));
+            assert_eq!(
+                assert_err!(SubscriberName::parse(name.clone())),
+                vec![SubscriberNameError::ForbiddenCharacters(name)]
+            );
         }
     }
 
+    #[test]
+    fn every_violated_rule_is_reported() {
+        let name = format!("<{}", "a".repeat(256));
+        assert_eq!(
+            assert_err!(SubscriberName::parse(name.clone())),
+            vec![
+                SubscriberNameError::TooLong,
+                SubscriberNameError::ForbiddenCharacters(name)
+            ]
+        );
+    }
+
+    #[test]
+    fn debug_output_does_not_reveal_the_name() {
+        let name = SubscriberName::parse("Ursula Le Guin".to_string()).unwrap();
+        assert_eq!(format!("{:?}", name), r#"SubscriberName("U***")"#);
+    }
+
     #[test]
     fn a_valid_name_is_parsed_successfully() {
         let name = "Ursula Le Guin".to_string();
@@ -0,0 +1,101 @@
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+const TOKEN_LENGTH: usize = 25;
+
+// the secret part of a confirmation link
+#[derive(Debug)]
+pub struct SubscriptionToken(String);
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum SubscriptionTokenError {
+    #[error("A subscription token must be {} characters long.", TOKEN_LENGTH)]
+    WrongLength,
+    #[error("A subscription token can only contain ASCII letters and digits.")]
+    ForbiddenCharacters,
+}
+
+impl SubscriptionToken {
+    /// Returns a new random token made of 25 alphanumeric characters.
+    pub fn generate() -> SubscriptionToken {
+        Self::generate_with(&mut thread_rng())
+    }
+
+    fn generate_with<R: Rng>(rng: &mut R) -> SubscriptionToken {
+        let token = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(TOKEN_LENGTH)
+            .collect();
+        Self(token)
+    }
+
+    /// Returns an instance of `SubscriptionToken` if the input looks like a
+    /// token we could have generated, so garbage never reaches the database.
+    pub fn parse(s: String) -> Result<SubscriptionToken, SubscriptionTokenError> {
+        if s.len() != TOKEN_LENGTH {
+            Err(SubscriptionTokenError::WrongLength)
+        } else if !s.chars().all(|c| c.is_ascii_alphanumeric()) {
+            Err(SubscriptionTokenError::ForbiddenCharacters)
+        } else {
+            Ok(Self(s))
+        }
+    }
+}
+impl AsRef<str> for SubscriptionToken {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claims::{assert_err, assert_ok};
+    use quickcheck::Arbitrary;
+    use rand::prelude::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(SubscriptionToken::parse("".to_string()));
+    }
+
+    #[test]
+    fn a_25_character_alphanumeric_token_is_valid() {
+        assert_ok!(SubscriptionToken::parse("a".repeat(25)));
+    }
+
+    #[test]
+    fn a_token_longer_than_25_characters_is_rejected() {
+        assert_err!(SubscriptionToken::parse("a".repeat(26)));
+    }
+
+    #[test]
+    fn a_token_with_non_ascii_alphanumeric_characters_is_rejected() {
+        for c in ['-', '_', '%', ' ', '<'] {
+            let token = format!("{}{}", "a".repeat(24), c);
+            assert_eq!(
+                assert_err!(SubscriptionToken::parse(token)),
+                SubscriptionTokenError::ForbiddenCharacters
+            );
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct GeneratedTokenFixture(pub String);
+    impl Arbitrary for GeneratedTokenFixture {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            let mut rng = StdRng::seed_from_u64(u64::arbitrary(g));
+            Self(SubscriptionToken::generate_with(&mut rng).0)
+        }
+    }
+    #[quickcheck_macros::quickcheck]
+    fn generated_tokens_are_parsed_successfully(token: GeneratedTokenFixture) -> bool {
+        SubscriptionToken::parse(token.0).is_ok()
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn strings_of_the_wrong_length_are_rejected(s: String) -> bool {
+        s.len() == TOKEN_LENGTH || SubscriptionToken::parse(s).is_err()
+    }
+}
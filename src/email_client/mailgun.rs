@@ -0,0 +1,166 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{list_unsubscribe_headers, probe_http, EmailError, EmailSender};
+use crate::telemetry::trace_context_headers;
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+
+pub struct MailgunClient {
+    sender: SubscriberEmail,
+    base_url: String,
+    domain: String,
+    http_client: Client,
+    api_key: Secret<String>,
+}
+
+impl MailgunClient {
+    pub fn new(
+        base_url: String,
+        domain: String,
+        sender: SubscriberEmail,
+        api_key: Secret<String>,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            sender,
+            base_url,
+            domain,
+            http_client,
+            api_key,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for MailgunClient {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError> {
+        let url = format!("{}/v3/{}/messages", self.base_url, self.domain);
+        let mut form = vec![
+            ("from".to_string(), self.sender.as_ref().to_string()),
+            ("to".to_string(), recipient.as_ref().to_string()),
+            ("subject".to_string(), subject.to_string()),
+            ("text".to_string(), text_content.to_string()),
+            ("html".to_string(), html_content.to_string()),
+        ];
+        //mailgun takes custom mime headers as form fields prefixed with `h:`
+        for (name, value) in list_unsubscribe_headers(unsubscribe_link) {
+            form.push((format!("h:{}", name), value));
+        }
+        self.http_client
+            .post(&url)
+            .headers(trace_context_headers())
+            .basic_auth("api", Some(self.api_key.expose_secret()))
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn probe(&self) -> Result<(), EmailError> {
+        probe_http(&self.http_client, &self.base_url).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::email_client::fixtures::{
+        content, email, send_with_unsubscribe_link, subject, UNSUBSCRIBE_LINK,
+    };
+    use crate::email_client::{EmailSender, MailgunClient};
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use std::collections::HashMap;
+    use wiremock::matchers::{any, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    fn form_fields(request: &Request) -> HashMap<String, String> {
+        url::form_urlencoded::parse(&request.body)
+            .into_owned()
+            .collect()
+    }
+
+    fn email_client(base_url: String) -> MailgunClient {
+        MailgunClient::new(
+            base_url,
+            "mg.example.com".into(),
+            email(),
+            Secret::new("mailgun-api-key".into()),
+            std::time::Duration::from_millis(200),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_email_posts_a_form_to_the_domain_messages_endpoint() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(header_exists("Authorization"))
+            .and(header("Content-Type", "application/x-www-form-urlencoded"))
+            .and(path("/v3/mg.example.com/messages"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let recipient = email();
+
+        //act
+        let outcome = email_client
+            .send_email(&recipient, &subject(), &content(), &content(), None)
+            .await;
+
+        //assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let fields = form_fields(request);
+        assert_eq!(fields["to"], recipient.as_ref());
+        for field in ["from", "subject", "text", "html"] {
+            assert!(fields.contains_key(field), "missing field {}", field);
+        }
+        assert!(!fields.contains_key("h:List-Unsubscribe"));
+    }
+
+    #[tokio::test]
+    async fn send_email_attaches_list_unsubscribe_headers_when_given_a_link() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        //act
+        let request = send_with_unsubscribe_link(&mock_server, &email_client, 200).await;
+
+        //assert
+        let fields = form_fields(&request);
+        assert_eq!(fields["h:List-Unsubscribe"], format!("<{}>", UNSUBSCRIBE_LINK));
+        assert_eq!(fields["h:List-Unsubscribe-Post"], "List-Unsubscribe=One-Click");
+    }
+
+    #[tokio::test]
+    async fn throttling_is_worth_retrying() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        //act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        //assert
+        let error = assert_err!(outcome);
+        assert!(error.is_transient());
+    }
+}
@@ -0,0 +1,47 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailError, EmailSender};
+use crate::metrics::Metrics;
+use std::sync::Arc;
+use std::time::Instant;
+
+//counts and times every send of the wrapped provider, whatever the provider is
+pub struct MeteredEmailSender {
+    inner: Arc<dyn EmailSender>,
+    provider: &'static str,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredEmailSender {
+    pub fn new(inner: Arc<dyn EmailSender>, provider: &'static str, metrics: Arc<Metrics>) -> Self {
+        Self {
+            inner,
+            provider,
+            metrics,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for MeteredEmailSender {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError> {
+        let start = Instant::now();
+        let outcome = self
+            .inner
+            .send_email(recipient, subject, html_content, text_content, unsubscribe_link)
+            .await;
+        self.metrics
+            .observe_email_send(self.provider, outcome.as_ref().map(|_| ()), start.elapsed());
+        outcome
+    }
+
+    async fn probe(&self) -> Result<(), EmailError> {
+        self.inner.probe().await
+    }
+}
@@ -0,0 +1,239 @@
+use crate::domain::SubscriberEmail;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::Message;
+use std::fmt;
+
+mod mailgun;
+mod metered;
+mod postmark;
+mod sendgrid;
+mod ses;
+mod sink;
+mod smtp;
+
+pub use mailgun::MailgunClient;
+pub use metered::MeteredEmailSender;
+pub use postmark::PostmarkClient;
+pub use sendgrid::SendGridClient;
+pub use ses::SesClient;
+pub use sink::{FileSink, StdoutSink};
+pub use smtp::SmtpClient;
+
+//routes and the delivery worker only talk to this trait, the provider is picked in the configuration
+#[async_trait::async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError>;
+
+    //a cheap reachability check for the readiness endpoint, nothing is sent
+    async fn probe(&self) -> Result<(), EmailError> {
+        Ok(())
+    }
+}
+
+//providers fail in different ways; callers only need to know whether asking again can help
+#[derive(Debug)]
+pub enum EmailError {
+    //throttling and provider outages
+    Transient(String),
+    //the provider could not be reached at all, nothing was sent
+    Unreachable(String),
+    //the provider did not answer in time, the email may or may not have been accepted
+    Timeout(String),
+    //the provider refused the message, sending it again will not change that
+    Rejected(String),
+}
+
+impl EmailError {
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            EmailError::Transient(_) | EmailError::Unreachable(_) | EmailError::Timeout(_)
+        )
+    }
+}
+
+impl fmt::Display for EmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmailError::Transient(e) => write!(f, "Temporary failure while sending an email: {}", e),
+            EmailError::Unreachable(e) => write!(f, "The email provider could not be reached: {}", e),
+            EmailError::Rejected(e) => write!(f, "The email provider rejected the email: {}", e),
+            EmailError::Timeout(e) => write!(f, "The email provider did not answer in time: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EmailError {}
+
+//shared by all the http based providers
+impl From<reqwest::Error> for EmailError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            return EmailError::Timeout(e.to_string());
+        }
+        if e.is_connect() {
+            return EmailError::Unreachable(e.to_string());
+        }
+        match e.status() {
+            Some(status) if status.is_client_error() && status.as_u16() != 429 => {
+                EmailError::Rejected(e.to_string())
+            }
+            _ => EmailError::Transient(e.to_string()),
+        }
+    }
+}
+
+//any answer from the API, even an error status, means the provider can be reached
+async fn probe_http(http_client: &reqwest::Client, base_url: &str) -> Result<(), EmailError> {
+    http_client
+        .get(base_url)
+        .send()
+        .await
+        .map_err(EmailError::from)?;
+    Ok(())
+}
+
+//RFC 8058 one-click unsubscribe, required by Gmail and Yahoo for bulk senders
+fn list_unsubscribe_headers(unsubscribe_link: Option<&str>) -> Vec<(&'static str, String)> {
+    match unsubscribe_link {
+        Some(link) => vec![
+            ("List-Unsubscribe", format!("<{}>", link)),
+            ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click".into()),
+        ],
+        None => Vec::new(),
+    }
+}
+
+//RFC 5322 message with a multipart/alternative body, for the backends that speak MIME themselves
+fn mime_message(
+    sender: &SubscriberEmail,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_content: &str,
+    text_content: &str,
+    unsubscribe_link: Option<&str>,
+) -> Result<Message, EmailError> {
+    let mut builder = Message::builder()
+        .from(mailbox(sender)?)
+        .to(mailbox(recipient)?)
+        .subject(subject);
+    for (name, value) in list_unsubscribe_headers(unsubscribe_link) {
+        builder = builder.raw_header(HeaderValue::new(HeaderName::new_from_ascii_str(name), value));
+    }
+    builder
+        .multipart(MultiPart::alternative_plain_html(
+            text_content.to_string(),
+            html_content.to_string(),
+        ))
+        .map_err(|e| EmailError::Rejected(e.to_string()))
+}
+
+fn mailbox(email: &SubscriberEmail) -> Result<Mailbox, EmailError> {
+    email
+        .as_ref()
+        .parse()
+        .map_err(|e| EmailError::Rejected(format!("Invalid address {}: {}", email.as_ref(), e)))
+}
+
+//fixtures shared by the provider tests, each provider only checks the shape of its own requests
+#[cfg(test)]
+mod fixtures {
+    use super::EmailSender;
+    use crate::domain::SubscriberEmail;
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::Fake;
+    use wiremock::matchers::any;
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    pub const UNSUBSCRIBE_LINK: &str = "https://example.com/subscriptions/unsubscribe?token=abc";
+
+    //generate a random email subject
+    pub fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    //generate a random email content
+    pub fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    //generate a random subscriber email
+    pub fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    //sends an email with an unsubscribe link to a provider answering `status`, returns what it received
+    pub async fn send_with_unsubscribe_link(
+        mock_server: &MockServer,
+        email_client: &impl EmailSender,
+        status: u16,
+    ) -> Request {
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(status))
+            .expect(1)
+            .mount(mock_server)
+            .await;
+        email_client
+            .send_email(
+                &email(),
+                &subject(),
+                &content(),
+                &content(),
+                Some(UNSUBSCRIBE_LINK),
+            )
+            .await
+            .unwrap();
+        mock_server.received_requests().await.unwrap().remove(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list_unsubscribe_headers, probe_http, EmailError};
+    use claims::{assert_err, assert_ok};
+    use wiremock::matchers::any;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn no_unsubscribe_link_means_no_list_headers() {
+        assert!(list_unsubscribe_headers(None).is_empty());
+    }
+
+    #[test]
+    fn the_unsubscribe_link_is_wrapped_in_angle_brackets() {
+        let headers = list_unsubscribe_headers(Some("https://example.com/u?token=abc"));
+        assert_eq!(headers[0], ("List-Unsubscribe", "<https://example.com/u?token=abc>".into()));
+        assert_eq!(
+            headers[1],
+            ("List-Unsubscribe-Post", "List-Unsubscribe=One-Click".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_succeeds_whatever_status_the_provider_answers_with() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        assert_ok!(probe_http(&reqwest::Client::new(), &mock_server.uri()).await);
+    }
+
+    #[tokio::test]
+    async fn probe_fails_if_the_provider_cannot_be_reached() {
+        //nothing listens on the discard port
+        let outcome = probe_http(&reqwest::Client::new(), "http://127.0.0.1:9").await;
+        assert!(matches!(assert_err!(outcome), EmailError::Unreachable(_)));
+    }
+}
@@ -1,4 +1,6 @@
 use crate::domain::SubscriberEmail;
+use crate::email_client::{list_unsubscribe_headers, probe_http, EmailError, EmailSender};
+use crate::telemetry::trace_context_headers;
 use reqwest::Client;
 use secrecy::{ExposeSecret, Secret};
 
@@ -10,16 +12,25 @@ struct SendEmailRequest<'a> {
     subject: &'a str,
     html_body: &'a str,
     text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<EmailHeader<'a>>,
 }
 
-pub struct EmailClient {
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailHeader<'a> {
+    name: &'a str,
+    value: String,
+}
+
+pub struct PostmarkClient {
     sender: SubscriberEmail,
     base_url: String,
     http_client: Client,
     authorization_token: Secret<String>,
 }
 
-impl EmailClient {
+impl PostmarkClient {
     pub fn new(
         base_url: String,
         sender: SubscriberEmail,
@@ -34,25 +45,34 @@ impl EmailClient {
             authorization_token,
         }
     }
-    pub async fn send_email(
+}
+
+#[async_trait::async_trait]
+impl EmailSender for PostmarkClient {
+    async fn send_email(
         &self,
-        recipient: SubscriberEmail,
+        recipient: &SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), reqwest::Error> {
-        //this needs to change
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError> {
         let url = format!("{}/email", self.base_url);
+        let headers = list_unsubscribe_headers(unsubscribe_link)
+            .into_iter()
+            .map(|(name, value)| EmailHeader { name, value })
+            .collect();
         let request_body = SendEmailRequest {
             from: self.sender.as_ref(),
             to: recipient.as_ref(),
             subject,
             html_body: html_content,
             text_body: text_content,
+            headers,
         };
-        let builder = self
-            .http_client
+        self.http_client
             .post(&url)
+            .headers(trace_context_headers())
             .header(
                 "X-Postmark-Server-Token", //this is as per postmark - a custom header
                 self.authorization_token.expose_secret(),
@@ -64,16 +84,18 @@ impl EmailClient {
         Ok(())
     }
 
-
+    async fn probe(&self) -> Result<(), EmailError> {
+        probe_http(&self.http_client, &self.base_url).await
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberEmail;
-    use crate::email_client::EmailClient;
+    use crate::email_client::fixtures::{
+        content, email, send_with_unsubscribe_link, subject, UNSUBSCRIBE_LINK,
+    };
+    use crate::email_client::{EmailError, EmailSender, PostmarkClient};
     use claims::{assert_ok, assert_err};
-    use fake::faker::internet::en::SafeEmail;
-    use fake::faker::lorem::en::{Paragraph, Sentence};
     use fake::{Fake, Faker};
     //use fake::faker::lorem::raw::Paragraph;
     use secrecy::Secret;
@@ -98,24 +120,9 @@ mod tests {
             }
         }
     }
-    //generate a random email subject
-    fn subject() -> String {
-        Sentence(1..2).fake()
-    }
-
-    //generate a random email content
-    fn content() -> String {
-        Paragraph(1..10).fake()
-    }
-
-    //generate a random subscriber email
-    fn email() -> SubscriberEmail {
-        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
-    }
-
-    //get a test instance of EmailClient
-    fn email_client(base_url: String) -> EmailClient {
-        EmailClient::new(
+    //get a test instance of PostmarkClient
+    fn email_client(base_url: String) -> PostmarkClient {
+        PostmarkClient::new(
             base_url,
             email(),
             Secret::new(Faker.fake()),
@@ -125,7 +132,6 @@ mod tests {
     #[tokio::test]
     async fn send_email_fires_a_request_to_base_url() {
         let mock_server = MockServer::start().await;
-        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
         let email_client = email_client(mock_server.uri());
         Mock::given(header_exists("X-Postmark-Server-Token")) //this is also as per postmark
             .and(header("Content-Type", "application/json")) //this might also be as per postmark
@@ -138,7 +144,7 @@ mod tests {
             .await;
         //act
         let _ = email_client
-            .send_email(email(), &subject(), &content(), &content())
+            .send_email(&email(), &subject(), &content(), &content(), None)
             .await;
         //assess
     }
@@ -148,7 +154,6 @@ mod tests {
     async fn send_email_succeeds_if_the_server_returns_200() {
         //arrange
         let mock_server = MockServer::start().await;
-        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
         let email_client = email_client(mock_server.uri());
 
         Mock::given(any())
@@ -158,7 +163,7 @@ mod tests {
             .await;
         //act
         let outcome = email_client
-            .send_email(email(), &subject(), &content(), &content())
+            .send_email(&email(), &subject(), &content(), &content(), None)
             .await;
         //assert
         assert_ok!(outcome);
@@ -168,7 +173,6 @@ mod tests {
     async fn send_email_fails_if_the_server_returns_500() {
         //arrange
         let mock_server = MockServer::start().await;
-        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
         let email_client = email_client(mock_server.uri());
 
         Mock::given(any())
@@ -178,29 +182,50 @@ mod tests {
             .await;
 
         //act
-        let outcome = email_client.send_email(email(), &subject(), &content(), &content()).await;
+        let outcome = email_client.send_email(&email(), &subject(), &content(), &content(), None).await;
 
         //assert
         assert_err!(outcome);
     }
 
-    //test for timeout
+    #[tokio::test]
+    async fn send_email_attaches_list_unsubscribe_headers_when_given_a_link() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        //act
+        let request = send_with_unsubscribe_link(&mock_server, &email_client, 200).await;
+
+        //assert
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["Headers"],
+            serde_json::json!([
+                {"Name": "List-Unsubscribe", "Value": format!("<{}>", UNSUBSCRIBE_LINK)},
+                {"Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click"}
+            ])
+        );
+    }
+
+    //test for timeout, retries and the metrics label both depend on it being told apart
+    #[tokio::test]
     async fn send_email_times_out_if_the_server_takes_too_long() {
         //Arrange
         let mock_server = MockServer::start().await;
-        let sender = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
         let email_client = email_client(mock_server.uri());
+        //well past the client's 200ms timeout
         let response = ResponseTemplate::new(200)
-            .set_delay(std::time::Duration::from_secs(180));
+            .set_delay(std::time::Duration::from_secs(2));
         Mock::given(any())
-            .respond_with(ResponseTemplate::new(500))
+            .respond_with(response)
             .expect(1)
             .mount(&mock_server)
             .await;
 
         //act
-        let outcome = email_client.send_email(email(), &subject(), &content(), &content()).await;
+        let outcome = email_client.send_email(&email(), &subject(), &content(), &content(), None).await;
         //assert
-        assert_err!(outcome);
+        assert!(matches!(outcome, Err(EmailError::Timeout(_))));
     }
 }
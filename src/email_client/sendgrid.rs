@@ -0,0 +1,211 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{list_unsubscribe_headers, probe_http, EmailError, EmailSender};
+use crate::telemetry::trace_context_headers;
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use std::collections::HashMap;
+
+//body of the SendGrid v3 mail send api
+#[derive(serde::Serialize)]
+struct SendEmailRequest<'a> {
+    personalizations: [Personalization<'a>; 1],
+    from: Address<'a>,
+    subject: &'a str,
+    content: [Content<'a>; 2],
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: HashMap<&'static str, String>,
+}
+
+#[derive(serde::Serialize)]
+struct Personalization<'a> {
+    to: [Address<'a>; 1],
+}
+
+#[derive(serde::Serialize)]
+struct Address<'a> {
+    email: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct Content<'a> {
+    #[serde(rename = "type")]
+    mime_type: &'a str,
+    value: &'a str,
+}
+
+pub struct SendGridClient {
+    sender: SubscriberEmail,
+    base_url: String,
+    http_client: Client,
+    api_key: Secret<String>,
+}
+
+impl SendGridClient {
+    pub fn new(
+        base_url: String,
+        sender: SubscriberEmail,
+        api_key: Secret<String>,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            sender,
+            base_url,
+            http_client,
+            api_key,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SendGridClient {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError> {
+        let url = format!("{}/v3/mail/send", self.base_url);
+        //sendgrid wants the plain text part before the html one
+        let request_body = SendEmailRequest {
+            personalizations: [Personalization {
+                to: [Address {
+                    email: recipient.as_ref(),
+                }],
+            }],
+            from: Address {
+                email: self.sender.as_ref(),
+            },
+            subject,
+            content: [
+                Content {
+                    mime_type: "text/plain",
+                    value: text_content,
+                },
+                Content {
+                    mime_type: "text/html",
+                    value: html_content,
+                },
+            ],
+            headers: list_unsubscribe_headers(unsubscribe_link).into_iter().collect(),
+        };
+        self.http_client
+            .post(&url)
+            .headers(trace_context_headers())
+            .bearer_auth(self.api_key.expose_secret())
+            .json(&request_body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn probe(&self) -> Result<(), EmailError> {
+        probe_http(&self.http_client, &self.base_url).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::email_client::fixtures::{
+        content, email, send_with_unsubscribe_link, subject, UNSUBSCRIBE_LINK,
+    };
+    use crate::email_client::{EmailSender, SendGridClient};
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use wiremock::matchers::{any, header, method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    struct SendEmailBodyMatcher;
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                body["personalizations"][0]["to"][0]["email"].is_string()
+                    && body["from"]["email"].is_string()
+                    && body["subject"].is_string()
+                    && body["content"][0]["type"] == "text/plain"
+                    && body["content"][1]["type"] == "text/html"
+            } else {
+                false
+            }
+        }
+    }
+
+    fn email_client(base_url: String) -> SendGridClient {
+        SendGridClient::new(
+            base_url,
+            email(),
+            Secret::new("sendgrid-api-key".into()),
+            std::time::Duration::from_millis(200),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request_to_the_mail_send_endpoint() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(header("Authorization", "Bearer sendgrid-api-key"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/v3/mail/send"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(202))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        //act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        //assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_attaches_list_unsubscribe_headers_when_given_a_link() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        //act
+        let request = send_with_unsubscribe_link(&mock_server, &email_client, 202).await;
+
+        //assert
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["headers"]["List-Unsubscribe"],
+            format!("<{}>", UNSUBSCRIBE_LINK)
+        );
+        assert_eq!(
+            body["headers"]["List-Unsubscribe-Post"],
+            "List-Unsubscribe=One-Click"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_bad_request_is_not_worth_retrying() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        //act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        //assert
+        let error = assert_err!(outcome);
+        assert!(!error.is_transient());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{list_unsubscribe_headers, probe_http, EmailError, EmailSender};
+use crate::telemetry::trace_context_headers;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+
+const SEND_EMAIL_PATH: &str = "/v2/email/outbound-emails";
+const SIGNED_HEADERS: &str = "content-type;host;x-amz-date";
+
+//body of the SES v2 SendEmail api
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from_email_address: &'a str,
+    destination: Destination<'a>,
+    content: EmailContent<'a>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Destination<'a> {
+    to_addresses: [&'a str; 1],
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailContent<'a> {
+    simple: SimpleMessage<'a>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SimpleMessage<'a> {
+    subject: Text<'a>,
+    body: Body<'a>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<MessageHeader>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Body<'a> {
+    text: Text<'a>,
+    html: Text<'a>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Text<'a> {
+    data: &'a str,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct MessageHeader {
+    name: &'static str,
+    value: String,
+}
+
+pub struct SesClient {
+    sender: SubscriberEmail,
+    base_url: String,
+    region: String,
+    http_client: Client,
+    access_key_id: String,
+    secret_access_key: Secret<String>,
+}
+
+impl SesClient {
+    pub fn new(
+        base_url: String,
+        region: String,
+        sender: SubscriberEmail,
+        access_key_id: String,
+        secret_access_key: Secret<String>,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            sender,
+            base_url,
+            region,
+            http_client,
+            access_key_id,
+            secret_access_key,
+        }
+    }
+
+    //AWS Signature Version 4 for a json POST, see
+    //https://docs.aws.amazon.com/IAM/latest/UserGuide/create-signed-request.html
+    fn authorization_header(&self, host: &str, body: &[u8], now: DateTime<Utc>) -> String {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let canonical_request = format!(
+            "POST\n{}\n\ncontent-type:application/json\nhost:{}\nx-amz-date:{}\n\n{}\n{}",
+            SEND_EMAIL_PATH,
+            host,
+            amz_date,
+            SIGNED_HEADERS,
+            hex::encode(Sha256::digest(body))
+        );
+        let scope = format!("{}/{}/ses/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(
+            self.secret_access_key.expose_secret(),
+            &date,
+            &self.region,
+            "ses",
+        );
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, SIGNED_HEADERS, signature
+        )
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, service.as_bytes());
+    hmac_sha256(&k_service, b"aws4_request")
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SesClient {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError> {
+        let url = reqwest::Url::parse(&format!("{}{}", self.base_url, SEND_EMAIL_PATH))
+            .map_err(|e| EmailError::Rejected(format!("Invalid SES endpoint: {}", e)))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(EmailError::Rejected("The SES endpoint has no host".into())),
+        };
+        let request_body = SendEmailRequest {
+            from_email_address: self.sender.as_ref(),
+            destination: Destination {
+                to_addresses: [recipient.as_ref()],
+            },
+            content: EmailContent {
+                simple: SimpleMessage {
+                    subject: Text { data: subject },
+                    body: Body {
+                        text: Text { data: text_content },
+                        html: Text { data: html_content },
+                    },
+                    headers: list_unsubscribe_headers(unsubscribe_link)
+                        .into_iter()
+                        .map(|(name, value)| MessageHeader { name, value })
+                        .collect(),
+                },
+            },
+        };
+        //the signature covers the exact bytes, so serialize once and send those
+        let body = serde_json::to_vec(&request_body)
+            .map_err(|e| EmailError::Rejected(e.to_string()))?;
+        let now = Utc::now();
+        let authorization = self.authorization_header(&host, &body, now);
+        self.http_client
+            .post(url)
+            .headers(trace_context_headers())
+            .header("Content-Type", "application/json")
+            .header("X-Amz-Date", now.format("%Y%m%dT%H%M%SZ").to_string())
+            .header("Authorization", authorization)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn probe(&self) -> Result<(), EmailError> {
+        probe_http(&self.http_client, &self.base_url).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::signing_key;
+    use crate::email_client::fixtures::{
+        content, email, send_with_unsubscribe_link, subject, UNSUBSCRIBE_LINK,
+    };
+    use crate::email_client::{EmailSender, SesClient};
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use wiremock::matchers::{any, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn email_client(base_url: String) -> SesClient {
+        SesClient::new(
+            base_url,
+            "eu-west-1".into(),
+            email(),
+            "AKIDEXAMPLE".into(),
+            Secret::new("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into()),
+            std::time::Duration::from_millis(200),
+        )
+    }
+
+    //example from the AWS Signature Version 4 documentation
+    #[test]
+    fn the_signing_key_matches_the_aws_example() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_signed_request_to_the_outbound_emails_endpoint() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(header_exists("Authorization"))
+            .and(header_exists("X-Amz-Date"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/v2/email/outbound-emails"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        //act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        //assert
+        assert_ok!(outcome);
+        //wiremock splits header values on commas, so the three parts come back separately
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let authorization: Vec<&str> = request.headers[&"Authorization".into()]
+            .iter()
+            .map(|value| value.as_str().trim())
+            .collect();
+        assert_eq!(authorization.len(), 3);
+        assert!(authorization[0].starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(authorization[0].ends_with("/eu-west-1/ses/aws4_request"));
+        assert_eq!(authorization[1], "SignedHeaders=content-type;host;x-amz-date");
+        let signature = authorization[2].strip_prefix("Signature=").unwrap();
+        assert_eq!(signature.len(), 64);
+        assert!(signature.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[tokio::test]
+    async fn send_email_attaches_list_unsubscribe_headers_when_given_a_link() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        //act
+        let request = send_with_unsubscribe_link(&mock_server, &email_client, 200).await;
+
+        //assert
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["Content"]["Simple"]["Headers"],
+            serde_json::json!([
+                {"Name": "List-Unsubscribe", "Value": format!("<{}>", UNSUBSCRIBE_LINK)},
+                {"Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click"}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn a_provider_outage_is_worth_retrying() {
+        //arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        //act
+        let outcome = email_client
+            .send_email(&email(), &subject(), &content(), &content(), None)
+            .await;
+
+        //assert
+        let error = assert_err!(outcome);
+        assert!(error.is_transient());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{mime_message, EmailError, EmailSender};
+use crate::telemetry::redact_email;
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+enum Layout {
+    //one `<timestamp>-<uuid>.eml` file per message
+    Eml,
+    //a Maildir, so the directory can be opened as a mailbox by mutt, Thunderbird & co
+    Maildir,
+}
+
+//development backend: nothing leaves the machine, every email ends up on disk
+pub struct FileSink {
+    sender: SubscriberEmail,
+    directory: PathBuf,
+    layout: Layout,
+}
+
+impl FileSink {
+    pub fn eml(directory: PathBuf, sender: SubscriberEmail) -> Self {
+        Self {
+            sender,
+            directory,
+            layout: Layout::Eml,
+        }
+    }
+
+    pub fn maildir(directory: PathBuf, sender: SubscriberEmail) -> Self {
+        Self {
+            sender,
+            directory,
+            layout: Layout::Maildir,
+        }
+    }
+
+    async fn write_eml(&self, message: &[u8]) -> std::io::Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let path = self.directory.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            Uuid::new_v4()
+        ));
+        tokio::fs::write(&path, message).await?;
+        Ok(path)
+    }
+
+    //messages are written to tmp/ and moved to new/, so a mail client never sees half a file
+    async fn write_maildir(&self, message: &[u8]) -> std::io::Result<PathBuf> {
+        for subdirectory in ["tmp", "new", "cur"] {
+            tokio::fs::create_dir_all(self.directory.join(subdirectory)).await?;
+        }
+        let name = format!("{}.{}.z2p", Utc::now().timestamp(), Uuid::new_v4().simple());
+        let tmp_path = self.directory.join("tmp").join(&name);
+        let path = self.directory.join("new").join(&name);
+        tokio::fs::write(&tmp_path, message).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(path)
+    }
+}
+
+fn io_error(directory: &Path, e: std::io::Error) -> EmailError {
+    EmailError::Transient(format!(
+        "Failed to write the email to {}: {}",
+        directory.display(),
+        e
+    ))
+}
+
+#[async_trait::async_trait]
+impl EmailSender for FileSink {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError> {
+        let message = mime_message(
+            &self.sender,
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            unsubscribe_link,
+        )?
+        .formatted();
+        let path = match self.layout {
+            Layout::Eml => self.write_eml(&message).await,
+            Layout::Maildir => self.write_maildir(&message).await,
+        }
+        .map_err(|e| io_error(&self.directory, e))?;
+        tracing::info!("Wrote email for {} to {}", redact_email(recipient.as_ref()), path.display());
+        Ok(())
+    }
+
+    async fn probe(&self) -> Result<(), EmailError> {
+        tokio::fs::create_dir_all(&self.directory)
+            .await
+            .map_err(|e| io_error(&self.directory, e))
+    }
+}
+
+//development backend: prints every email to standard output
+pub struct StdoutSink {
+    sender: SubscriberEmail,
+}
+
+impl StdoutSink {
+    pub fn new(sender: SubscriberEmail) -> Self {
+        Self { sender }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for StdoutSink {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError> {
+        let mut message = mime_message(
+            &self.sender,
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            unsubscribe_link,
+        )?
+        .formatted();
+        message.extend_from_slice(b"\r\n");
+        let mut stdout = tokio::io::stdout();
+        stdout
+            .write_all(&message)
+            .await
+            .map_err(|e| EmailError::Transient(e.to_string()))?;
+        stdout
+            .flush()
+            .await
+            .map_err(|e| EmailError::Transient(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailSender, FileSink};
+    use std::path::{Path, PathBuf};
+    use uuid::Uuid;
+
+    fn directory() -> PathBuf {
+        std::env::temp_dir().join(format!("z2p-emails-{}", Uuid::new_v4()))
+    }
+
+    fn sender() -> SubscriberEmail {
+        SubscriberEmail::parse("newsletter@example.com".into()).unwrap()
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula_le_guin@example.com".into()).unwrap()
+    }
+
+    fn files_in(directory: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn every_email_is_written_to_its_own_eml_file() {
+        //arrange
+        let directory = directory();
+        let sink = FileSink::eml(directory.clone(), sender());
+
+        //act
+        sink.send_email(
+            &recipient(),
+            "Welcome!",
+            "<p>Click <a href=\"http://127.0.0.1/confirm\">here</a></p>",
+            "Visit http://127.0.0.1/confirm",
+            None,
+        )
+        .await
+        .unwrap();
+        sink.send_email(&recipient(), "Issue #1", "<p>Hi</p>", "Hi", None)
+            .await
+            .unwrap();
+
+        //assert
+        let files = files_in(&directory);
+        assert_eq!(files.len(), 2);
+        assert!(files
+            .iter()
+            .all(|file| file.extension().unwrap() == "eml"));
+        let welcome = files
+            .iter()
+            .map(|file| std::fs::read_to_string(file).unwrap())
+            .find(|content| content.contains("Subject: Welcome!"))
+            .unwrap();
+        assert!(welcome.contains("From: newsletter@example.com"));
+        assert!(welcome.contains("To: ursula_le_guin@example.com"));
+        assert!(welcome.contains("multipart/alternative"));
+        assert!(welcome.contains("http://127.0.0.1/confirm"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn maildir_entries_are_delivered_to_new() {
+        //arrange
+        let directory = directory();
+        let sink = FileSink::maildir(directory.clone(), sender());
+
+        //act
+        sink.send_email(
+            &recipient(),
+            "Issue #1",
+            "<p>Hi</p>",
+            "Hi",
+            Some("http://127.0.0.1/subscriptions/unsubscribe?token=abc"),
+        )
+        .await
+        .unwrap();
+
+        //assert
+        assert!(files_in(&directory.join("tmp")).is_empty());
+        assert!(files_in(&directory.join("cur")).is_empty());
+        let delivered = files_in(&directory.join("new"));
+        assert_eq!(delivered.len(), 1);
+        let content = std::fs::read_to_string(&delivered[0]).unwrap();
+        assert!(content.contains("Subject: Issue #1"));
+        assert!(content
+            .contains("List-Unsubscribe: <http://127.0.0.1/subscriptions/unsubscribe?token=abc>"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{mime_message, EmailError, EmailSender};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+//the transport is built from `SmtpSettings` and keeps a pool of open connections to the relay
+pub struct SmtpClient {
+    sender: SubscriberEmail,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpClient {
+    pub fn new(transport: AsyncSmtpTransport<Tokio1Executor>, sender: SubscriberEmail) -> Self {
+        Self { sender, transport }
+    }
+}
+
+//4xx replies, timeouts and connection problems can succeed later, 5xx replies will not
+fn classify(e: lettre::transport::smtp::Error) -> EmailError {
+    if e.is_timeout() {
+        EmailError::Timeout(e.to_string())
+    } else if e.is_permanent() || e.is_client() {
+        EmailError::Rejected(e.to_string())
+    } else if e.is_transient() {
+        EmailError::Transient(e.to_string())
+    } else {
+        //connection, network and tls failures, the relay never answered
+        EmailError::Unreachable(e.to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SmtpClient {
+    async fn send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_link: Option<&str>,
+    ) -> Result<(), EmailError> {
+        let message = mime_message(
+            &self.sender,
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            unsubscribe_link,
+        )?;
+        self.transport.send(message).await.map_err(classify)?;
+        Ok(())
+    }
+
+    async fn probe(&self) -> Result<(), EmailError> {
+        match self.transport.test_connection().await.map_err(classify)? {
+            true => Ok(()),
+            false => Err(EmailError::Transient("The relay did not answer NOOP".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{SmtpAuthMechanism, SmtpSettings, SmtpTls};
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::fixtures::UNSUBSCRIBE_LINK;
+    use crate::email_client::{EmailSender, SmtpClient};
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    //just enough of an SMTP relay to accept messages: it advertises AUTH PLAIN and LOGIN,
+    //records every command and forwards every received DATA section on `messages`
+    struct SmtpSink {
+        port: u16,
+        messages: mpsc::UnboundedReceiver<String>,
+        commands: Arc<Mutex<Vec<String>>>,
+        connections: Arc<AtomicUsize>,
+    }
+
+    async fn start_smtp_sink(reject_recipients: bool) -> SmtpSink {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, messages) = mpsc::unbounded_channel();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let (recorded, accepted) = (commands.clone(), connections.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let (sender, recorded) = (sender.clone(), recorded.clone());
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+                    let mut data: Option<String> = None;
+                    //AUTH LOGIN sends the username and the password on lines of their own
+                    let mut login_lines = 0;
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if let Some(message) = data.as_mut() {
+                            if line == "." {
+                                sender.send(data.take().unwrap()).unwrap();
+                                writer.write_all(b"250 Queued\r\n").await.unwrap();
+                            } else {
+                                message.push_str(&line);
+                                message.push('\n');
+                            }
+                            continue;
+                        }
+                        if login_lines > 0 {
+                            login_lines -= 1;
+                            let reply: &[u8] = if login_lines == 1 {
+                                b"334 UGFzc3dvcmQ6\r\n"
+                            } else {
+                                b"235 Authenticated\r\n"
+                            };
+                            writer.write_all(reply).await.unwrap();
+                            continue;
+                        }
+                        let command = line.to_uppercase();
+                        recorded.lock().unwrap().push(command.clone());
+                        let reply: &[u8] = if command.starts_with("EHLO") {
+                            b"250-localhost\r\n250 AUTH PLAIN LOGIN\r\n"
+                        } else if command.starts_with("AUTH PLAIN") {
+                            b"235 Authenticated\r\n"
+                        } else if command.starts_with("AUTH LOGIN") {
+                            login_lines = 2;
+                            b"334 VXNlcm5hbWU6\r\n"
+                        } else if command.starts_with("RCPT") && reject_recipients {
+                            b"550 No such user\r\n"
+                        } else if command.starts_with("DATA") {
+                            data = Some(String::new());
+                            b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                        } else if command.starts_with("QUIT") {
+                            writer.write_all(b"221 Bye\r\n").await.unwrap();
+                            break;
+                        } else {
+                            b"250 OK\r\n"
+                        };
+                        writer.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        SmtpSink {
+            port,
+            messages,
+            commands,
+            connections,
+        }
+    }
+
+    fn settings(port: u16) -> SmtpSettings {
+        SmtpSettings {
+            host: "127.0.0.1".into(),
+            port: Some(port),
+            username: None,
+            tls: SmtpTls::None,
+            auth_mechanisms: None,
+            pool_max_size: None,
+            pool_idle_timeout_seconds: None,
+        }
+    }
+
+    fn email_client(settings: SmtpSettings) -> SmtpClient {
+        let transport = settings
+            .transport(
+                Secret::new("relay-password".into()),
+                std::time::Duration::from_secs(2),
+            )
+            .unwrap();
+        SmtpClient::new(
+            transport,
+            SubscriberEmail::parse("newsletter@example.com".into()).unwrap(),
+        )
+    }
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula_le_guin@example.com".into()).unwrap()
+    }
+
+    fn auth_commands(sink: &SmtpSink) -> Vec<String> {
+        sink.commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|command| command.starts_with("AUTH"))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_email_delivers_a_multipart_message() {
+        //arrange
+        let mut sink = start_smtp_sink(false).await;
+        let email_client = email_client(settings(sink.port));
+
+        //act
+        let outcome = email_client
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello", None)
+            .await;
+
+        //assert
+        assert_ok!(outcome);
+        let message = sink.messages.recv().await.unwrap();
+        assert!(message.contains("To: ursula_le_guin@example.com"));
+        assert!(message.contains("Subject: Welcome!"));
+        assert!(message.contains("multipart/alternative"));
+        assert!(message.contains("text/plain"));
+        assert!(message.contains("text/html"));
+        assert!(!message.contains("List-Unsubscribe"));
+        assert!(auth_commands(&sink).is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_email_attaches_list_unsubscribe_headers_when_given_a_link() {
+        //arrange
+        let mut sink = start_smtp_sink(false).await;
+        let email_client = email_client(settings(sink.port));
+
+        //act
+        email_client
+            .send_email(
+                &recipient(),
+                "Issue #1",
+                "<p>Hello</p>",
+                "Hello",
+                Some(UNSUBSCRIBE_LINK),
+            )
+            .await
+            .unwrap();
+
+        //assert
+        let message = sink.messages.recv().await.unwrap();
+        assert!(message.contains(&format!("List-Unsubscribe: <{}>", UNSUBSCRIBE_LINK)));
+        assert!(message.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+    }
+
+    #[tokio::test]
+    async fn credentials_are_sent_with_auth_plain_by_default() {
+        //arrange
+        let sink = start_smtp_sink(false).await;
+        let email_client = email_client(SmtpSettings {
+            username: Some("newsletter".into()),
+            ..settings(sink.port)
+        });
+
+        //act
+        email_client
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello", None)
+            .await
+            .unwrap();
+
+        //assert
+        let auth = auth_commands(&sink);
+        assert_eq!(auth.len(), 1);
+        assert!(auth[0].starts_with("AUTH PLAIN "));
+    }
+
+    #[tokio::test]
+    async fn auth_login_is_used_when_configured() {
+        //arrange
+        let sink = start_smtp_sink(false).await;
+        let email_client = email_client(SmtpSettings {
+            username: Some("newsletter".into()),
+            auth_mechanisms: Some(vec![SmtpAuthMechanism::Login]),
+            ..settings(sink.port)
+        });
+
+        //act
+        let outcome = email_client
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello", None)
+            .await;
+
+        //assert
+        assert_ok!(outcome);
+        assert_eq!(auth_commands(&sink), vec!["AUTH LOGIN".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused_between_emails() {
+        //arrange
+        let mut sink = start_smtp_sink(false).await;
+        let email_client = email_client(settings(sink.port));
+
+        //act
+        for _ in 0..3 {
+            email_client
+                .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello", None)
+                .await
+                .unwrap();
+            sink.messages.recv().await.unwrap();
+            //lettre hands the connection back to the pool from a spawned task
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        //assert
+        assert_eq!(sink.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn starttls_is_required_when_configured() {
+        //arrange
+        let sink = start_smtp_sink(false).await;
+        let email_client = email_client(SmtpSettings {
+            tls: SmtpTls::StartTls,
+            ..settings(sink.port)
+        });
+
+        //act
+        let outcome = email_client
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello", None)
+            .await;
+
+        //assert - the sink does not offer STARTTLS, so nothing may be sent in plaintext
+        assert_err!(outcome);
+        assert!(!sink.commands.lock().unwrap().iter().any(|c| c.starts_with("MAIL")));
+    }
+
+    #[tokio::test]
+    async fn a_permanent_smtp_failure_is_not_worth_retrying() {
+        //arrange
+        let sink = start_smtp_sink(true).await;
+        let email_client = email_client(settings(sink.port));
+
+        //act
+        let outcome = email_client
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello", None)
+            .await;
+
+        //assert
+        let error = assert_err!(outcome);
+        assert!(!error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_server_is_worth_retrying() {
+        //arrange
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let email_client = email_client(settings(port));
+
+        //act
+        let outcome = email_client
+            .send_email(&recipient(), "Welcome!", "<p>Hello</p>", "Hello", None)
+            .await;
+
+        //assert
+        let error = assert_err!(outcome);
+        assert!(error.is_transient());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailSender;
+use crate::issue_delivery_worker::{backoff_delay, pause, ExecutionOutcome};
+use crate::metrics::Metrics;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+//the email is only visible to the dispatcher once the caller's transaction commits,
+//and it is gone if that transaction rolls back
+#[tracing::instrument(name = "Add email to the outbox", skip_all)]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_content: &str,
+    text_content: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let email_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_outbox (email_id, recipient, subject, html_content, text_content)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        email_id,
+        recipient.as_ref(),
+        subject,
+        html_content,
+        text_content
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(email_id)
+}
+
+//people are waiting for these emails, so the outbox is polled far more often than the newsletter queue
+pub async fn dispatcher_loop(
+    pool: PgPool,
+    metrics: Arc<Metrics>,
+    email_client: Arc<dyn EmailSender>,
+    max_attempts: i16,
+    shutdown: CancellationToken,
+) {
+    //like the delivery worker, an email being sent is always finished before stopping
+    while !shutdown.is_cancelled() {
+        match try_dispatch_email(&pool, &metrics, email_client.as_ref(), max_attempts).await {
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Ok(ExecutionOutcome::EmptyQueue | ExecutionOutcome::TaskFailed) | Err(_) => {
+                pause(&shutdown, Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(email_id=tracing::field::Empty, n_retries=tracing::field::Empty),
+    err(Debug)
+)]
+pub async fn try_dispatch_email(
+    pool: &PgPool,
+    metrics: &Metrics,
+    email_client: &dyn EmailSender,
+    max_attempts: i16,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let (transaction, email) = match dequeue_email(pool, metrics).await? {
+        Some(email) => email,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current()
+        .record("email_id", display(email.email_id))
+        .record("n_retries", email.n_retries);
+    let recipient = match SubscriberEmail::parse(email.recipient.clone()) {
+        Ok(recipient) => recipient,
+        Err(e) => {
+            tracing::error!("Dropping an email with an invalid recipient: {}", e);
+            mark_as_failed(transaction, email.email_id, &e.to_string()).await?;
+            return Ok(ExecutionOutcome::TaskFailed);
+        }
+    };
+    if let Err(e) = email_client
+        .send_email(
+            &recipient,
+            &email.subject,
+            &email.html_content,
+            &email.text_content,
+            None,
+        )
+        .await
+    {
+        let n_attempts = email.n_retries + 1;
+        if e.is_transient() && n_attempts < max_attempts {
+            tracing::warn!("Failed to send an email from the outbox. Retrying later: {:?}", e);
+            reschedule_email(transaction, email.email_id, backoff_delay(email.n_retries)).await?;
+        } else {
+            tracing::error!(
+                "Failed to send an email from the outbox. Giving up after {} attempts: {:?}",
+                n_attempts,
+                e
+            );
+            mark_as_failed(transaction, email.email_id, &e.to_string()).await?;
+        }
+        return Ok(ExecutionOutcome::TaskFailed);
+    }
+    delete_email(transaction, email.email_id).await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+struct OutboxEmail {
+    email_id: Uuid,
+    recipient: String,
+    subject: String,
+    html_content: String,
+    text_content: String,
+    n_retries: i16,
+}
+
+#[tracing::instrument(skip_all)]
+async fn dequeue_email(
+    pool: &PgPool,
+    metrics: &Metrics,
+) -> Result<Option<(PgTransaction, OutboxEmail)>, sqlx::Error> {
+    let mut transaction = metrics.begin_transaction(pool).await?;
+    let email = sqlx::query_as!(
+        OutboxEmail,
+        r#"
+        SELECT email_id, recipient, subject, html_content, text_content, n_retries
+        FROM email_outbox
+        WHERE failed_at IS NULL AND execute_after <= now()
+        ORDER BY execute_after
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    Ok(email.map(|email| (transaction, email)))
+}
+
+#[tracing::instrument(skip_all)]
+async fn reschedule_email(
+    mut transaction: PgTransaction,
+    email_id: Uuid,
+    delay: Duration,
+) -> Result<(), sqlx::Error> {
+    let execute_after = Utc::now()
+        + chrono::Duration::from_std(delay).expect("Retry delay is out of range");
+    sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET n_retries = n_retries + 1, execute_after = $2
+        WHERE email_id = $1
+        "#,
+        email_id,
+        execute_after
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn mark_as_failed(
+    mut transaction: PgTransaction,
+    email_id: Uuid,
+    last_error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET n_retries = n_retries + 1, failed_at = now(), last_error = $2
+        WHERE email_id = $1
+        "#,
+        email_id,
+        last_error
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_email(mut transaction: PgTransaction, email_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM email_outbox WHERE email_id = $1"#, email_id)
+        .execute(&mut transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
@@ -0,0 +1,8 @@
+mod key;
+mod persistence;
+
+pub use key::{idempotency_key_from_request, IdempotencyKey};
+pub use persistence::{
+    begin_processing, complete_processing, request_hash, save_response, try_processing,
+    IdempotencyRetention, NextAction, ANONYMOUS_USER_ID,
+};
@@ -0,0 +1,223 @@
+use super::IdempotencyKey;
+use crate::metrics::Metrics;
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgHasArrayType;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+//subscribers are anonymous, their keys all live under the nil user;
+//the request hash keeps one visitor's key from answering another visitor's request
+pub const ANONYMOUS_USER_ID: Uuid = Uuid::nil();
+
+//fingerprint of what a request asks for, the key itself is left out wherever it was sent
+pub fn request_hash(fields: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for field in fields {
+        //length-prefixed, so ("ab", "c") and ("a", "bc") do not collide
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+//saved responses older than this are forgotten and the key can be used again
+#[derive(Clone, Copy)]
+pub struct IdempotencyRetention(pub chrono::Duration);
+
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+struct HeaderPairRecord {
+    name: String,
+    value: Vec<u8>,
+}
+
+impl PgHasArrayType for HeaderPairRecord {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_header_pair")
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum NextAction {
+    //the transaction holds the lock on the key, do the work inside it and hand it to `save_response`
+    StartProcessing(Transaction<'static, Postgres>),
+    ReturnSavedResponse(HttpResponse),
+}
+
+#[tracing::instrument(name = "Try processing an idempotent request", skip(pool, metrics, retention))]
+pub async fn try_processing(
+    pool: &PgPool,
+    metrics: &Metrics,
+    idempotency_key: &IdempotencyKey,
+    request_hash: &str,
+    user_id: Uuid,
+    retention: IdempotencyRetention,
+) -> Result<NextAction, anyhow::Error> {
+    sqlx::query!(
+        r#"DELETE FROM idempotency WHERE created_at < $1"#,
+        Utc::now() - retention.0
+    )
+    .execute(pool)
+    .await?;
+
+    let mut transaction = metrics.begin_transaction(pool).await?;
+    //a concurrent request with the same key blocks here until the first one commits or rolls back
+    let n_inserted_rows = sqlx::query!(
+        r#"
+        INSERT INTO idempotency (
+            user_id,
+            idempotency_key,
+            request_hash,
+            created_at
+        )
+        VALUES ($1, $2, $3, now())
+        ON CONFLICT DO NOTHING
+        "#,
+        user_id,
+        idempotency_key.as_ref(),
+        request_hash
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    if n_inserted_rows > 0 {
+        Ok(NextAction::StartProcessing(transaction))
+    } else {
+        match get_saved_response(pool, idempotency_key, request_hash, user_id).await? {
+            Some(saved_response) => Ok(NextAction::ReturnSavedResponse(saved_response)),
+            //should not happen since the row and its response are committed together, but never replay an empty answer
+            None => Ok(NextAction::ReturnSavedResponse(
+                HttpResponse::Conflict().finish(),
+            )),
+        }
+    }
+}
+
+#[tracing::instrument(name = "Get saved response", skip(pool))]
+async fn get_saved_response(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    request_hash: &str,
+    user_id: Uuid,
+) -> Result<Option<HttpResponse>, anyhow::Error> {
+    let saved_response = sqlx::query!(
+        r#"
+        SELECT
+            request_hash,
+            response_status_code as "response_status_code!",
+            response_headers as "response_headers!: Vec<HeaderPairRecord>",
+            response_body as "response_body!"
+        FROM idempotency
+        WHERE
+          user_id = $1 AND
+          idempotency_key = $2
+        "#,
+        user_id,
+        idempotency_key.as_ref()
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(r) = saved_response {
+        //replaying the answer to another request would silently drop this one
+        if r.request_hash.is_some_and(|saved| saved != request_hash) {
+            return Ok(Some(
+                HttpResponse::UnprocessableEntity()
+                    .body("The idempotency key was already used for a different request."),
+            ));
+        }
+        let status_code = StatusCode::from_u16(r.response_status_code.try_into()?)?;
+        let mut response = HttpResponse::build(status_code);
+        for HeaderPairRecord { name, value } in r.response_headers {
+            response.append_header((name, value));
+        }
+        Ok(Some(response.body(r.response_body)))
+    } else {
+        Ok(None)
+    }
+}
+
+#[tracing::instrument(name = "Save response", skip(transaction, http_response))]
+pub async fn save_response(
+    mut transaction: Transaction<'static, Postgres>,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+    http_response: HttpResponse,
+) -> Result<HttpResponse, anyhow::Error> {
+    let (response_head, body) = http_response.into_parts();
+    //`MessageBody::Error` is not `Sync`, so it cannot go into an `anyhow::Error` as is
+    let body = to_bytes(body).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+    let status_code = response_head.status().as_u16() as i16;
+    let headers = {
+        let mut h = Vec::with_capacity(response_head.headers().len());
+        for (name, value) in response_head.headers().iter() {
+            let name = name.as_str().to_owned();
+            let value = value.as_bytes().to_owned();
+            h.push(HeaderPairRecord { name, value });
+        }
+        h
+    };
+    //the macro cannot check custom composite types, hence the unchecked query
+    sqlx::query_unchecked!(
+        r#"
+        UPDATE idempotency
+        SET
+            response_status_code = $3,
+            response_headers = $4,
+            response_body = $5
+        WHERE
+            user_id = $1 AND
+            idempotency_key = $2
+        "#,
+        user_id,
+        idempotency_key.as_ref(),
+        status_code,
+        headers,
+        body.as_ref()
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+
+    //the body has been consumed, rebuild the response from its parts
+    let http_response = response_head.set_body(body).map_into_boxed_body();
+    Ok(http_response)
+}
+
+//requests without a key simply run in a plain transaction
+pub async fn begin_processing(
+    pool: &PgPool,
+    metrics: &Metrics,
+    idempotency_key: Option<&IdempotencyKey>,
+    request_hash: &str,
+    user_id: Uuid,
+    retention: IdempotencyRetention,
+) -> Result<NextAction, anyhow::Error> {
+    match idempotency_key {
+        Some(idempotency_key) => {
+            try_processing(pool, metrics, idempotency_key, request_hash, user_id, retention).await
+        }
+        None => Ok(NextAction::StartProcessing(metrics.begin_transaction(pool).await?)),
+    }
+}
+
+//commits the work done inside the transaction, storing the response first when a key was given
+pub async fn complete_processing(
+    transaction: Transaction<'static, Postgres>,
+    idempotency_key: Option<&IdempotencyKey>,
+    user_id: Uuid,
+    http_response: HttpResponse,
+) -> Result<HttpResponse, anyhow::Error> {
+    match idempotency_key {
+        Some(idempotency_key) => {
+            save_response(transaction, idempotency_key, user_id, http_response).await
+        }
+        None => {
+            transaction.commit().await?;
+            Ok(http_response)
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailSender;
+use crate::metrics::Metrics;
+use crate::telemetry::redact_email;
+use chrono::Utc;
+use rand::{thread_rng, Rng};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+//delay before the first retry, doubled after every failed attempt
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    TaskFailed,
+    EmptyQueue,
+}
+
+//drains the delivery queue forever; several replicas can run this at the same time
+pub async fn worker_loop(
+    pool: PgPool,
+    metrics: Arc<Metrics>,
+    email_client: Arc<dyn EmailSender>,
+    base_url: String,
+    max_attempts: i16,
+    shutdown: CancellationToken,
+) {
+    //the token is only checked between tasks, so a delivery in flight is always finished
+    while !shutdown.is_cancelled() {
+        match try_execute_task(&pool, &metrics, email_client.as_ref(), &base_url, max_attempts).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                pause(&shutdown, Duration::from_secs(10)).await;
+            }
+            //back off for a bit so a failing provider or database is not hammered
+            Ok(ExecutionOutcome::TaskFailed) | Err(_) => {
+                pause(&shutdown, Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
+
+//sleeps between polls, cut short on shutdown
+pub(crate) async fn pause(shutdown: &CancellationToken, duration: Duration) {
+    tokio::select! {
+        _ = shutdown.cancelled() => {}
+        _ = tokio::time::sleep(duration) => {}
+    }
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        newsletter_issue_id=tracing::field::Empty,
+        subscriber_email=tracing::field::Empty,
+        n_retries=tracing::field::Empty
+    ),
+    err(Debug)
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    metrics: &Metrics,
+    email_client: &dyn EmailSender,
+    base_url: &str,
+    max_attempts: i16,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let (transaction, task) = match dequeue_task(pool, metrics).await? {
+        Some(task) => task,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current()
+        .record("newsletter_issue_id", display(task.newsletter_issue_id))
+        .record("subscriber_email", display(redact_email(&task.subscriber_email)))
+        .record("n_retries", task.n_retries);
+    //the subscriber may have left the list after the issue was enqueued
+    let unsubscribe_token = match get_unsubscribe_token(pool, &task.subscriber_email).await? {
+        Some(token) => token,
+        None => {
+            tracing::info!("Skipping a subscriber who is no longer confirmed");
+            delete_task(transaction, task.newsletter_issue_id, &task.subscriber_email).await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    };
+    match SubscriberEmail::parse(task.subscriber_email.clone()) {
+        Ok(email) => {
+            let issue = get_issue(pool, task.newsletter_issue_id).await?;
+            let unsubscribe_link = format!(
+                "{}/subscriptions/unsubscribe?token={}",
+                base_url, unsubscribe_token
+            );
+            if let Err(e) = email_client
+                .send_email(
+                    &email,
+                    &issue.title,
+                    &issue.html_content,
+                    &issue.text_content,
+                    Some(&unsubscribe_link),
+                )
+                .await
+            {
+                let n_attempts = task.n_retries + 1;
+                //a 4xx will not go away by asking again, so there is no point in retrying it
+                if e.is_transient() && n_attempts < max_attempts {
+                    tracing::warn!(
+                        "Failed to deliver issue to a confirmed subscriber. Retrying later: {:?}",
+                        e
+                    );
+                    reschedule_task(transaction, &task, backoff_delay(task.n_retries)).await?;
+                } else {
+                    tracing::error!(
+                        "Failed to deliver issue to a confirmed subscriber. Giving up after {} attempts: {:?}",
+                        n_attempts,
+                        e
+                    );
+                    dead_letter_task(transaction, &task, n_attempts, &e.to_string()).await?;
+                }
+                return Ok(ExecutionOutcome::TaskFailed);
+            }
+        }
+        Err(e) => {
+            tracing::error!(
+                "Skipping a confirmed subscriber. Their stored contact details are invalid: {}",
+                e
+            );
+        }
+    }
+    delete_task(transaction, task.newsletter_issue_id, &task.subscriber_email).await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+//exponential backoff with jitter, so that replicas retrying the same outage do not fire in lockstep
+pub(crate) fn backoff_delay(n_retries: i16) -> Duration {
+    let exponent = n_retries.clamp(0, 16) as u32;
+    let delay = BASE_RETRY_DELAY
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_RETRY_DELAY);
+    let half = delay / 2;
+    half + half.mul_f64(thread_rng().gen::<f64>())
+}
+
+struct Task {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_retries: i16,
+}
+
+#[tracing::instrument(skip_all)]
+async fn dequeue_task(
+    pool: &PgPool,
+    metrics: &Metrics,
+) -> Result<Option<(PgTransaction, Task)>, sqlx::Error> {
+    let mut transaction = metrics.begin_transaction(pool).await?;
+    //SKIP LOCKED lets concurrent workers pick different rows instead of waiting on each other
+    let task = sqlx::query_as!(
+        Task,
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, n_retries
+        FROM issue_delivery_queue
+        WHERE execute_after <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    Ok(task.map(|task| (transaction, task)))
+}
+
+#[tracing::instrument(skip_all)]
+async fn reschedule_task(
+    mut transaction: PgTransaction,
+    task: &Task,
+    delay: Duration,
+) -> Result<(), sqlx::Error> {
+    let execute_after = Utc::now()
+        + chrono::Duration::from_std(delay).expect("Retry delay is out of range");
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            n_retries = n_retries + 1,
+            execute_after = $3
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        execute_after
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn dead_letter_task(
+    mut transaction: PgTransaction,
+    task: &Task,
+    n_attempts: i16,
+    last_error: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_dead_letters (
+            newsletter_issue_id,
+            subscriber_email,
+            n_attempts,
+            last_error,
+            failed_at
+        )
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (newsletter_issue_id, subscriber_email) DO UPDATE
+        SET
+            n_attempts = EXCLUDED.n_attempts,
+            last_error = EXCLUDED.last_error,
+            failed_at = EXCLUDED.failed_at
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        n_attempts,
+        last_error,
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await?;
+    //removing the task commits the move out of the queue
+    delete_task(transaction, task.newsletter_issue_id, &task.subscriber_email).await
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_task(
+    mut transaction: PgTransaction,
+    issue_id: Uuid,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM issue_delivery_queue
+        WHERE
+            newsletter_issue_id = $1 AND
+            subscriber_email = $2
+        "#,
+        issue_id,
+        email
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_unsubscribe_token(pool: &PgPool, email: &str) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT unsubscribe_token
+        FROM subscriptions
+        WHERE email = $1 AND status = 'confirmed'
+        "#,
+        email
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.unsubscribe_token))
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, sqlx::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT title, text_content, html_content
+        FROM newsletter_issues
+        WHERE
+            newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(issue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff_delay, BASE_RETRY_DELAY, MAX_RETRY_DELAY};
+
+    #[test]
+    fn the_first_retry_waits_about_the_base_delay() {
+        let delay = backoff_delay(0);
+        assert!(delay >= BASE_RETRY_DELAY / 2);
+        assert!(delay <= BASE_RETRY_DELAY);
+    }
+
+    #[test]
+    fn the_delay_doubles_with_every_retry() {
+        for n_retries in 0..8 {
+            let upper_bound = BASE_RETRY_DELAY * 2u32.pow(n_retries as u32);
+            let delay = backoff_delay(n_retries);
+            assert!(delay >= upper_bound / 2);
+            assert!(delay <= upper_bound);
+        }
+    }
+
+    #[test]
+    fn the_delay_is_capped() {
+        for n_retries in [20, 100, i16::MAX] {
+            assert!(backoff_delay(n_retries) <= MAX_RETRY_DELAY);
+        }
+    }
+}
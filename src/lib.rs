@@ -1,9 +1,17 @@
+pub mod authentication;
 pub mod configuration;
 pub mod domain;
 pub mod email_client;
+pub mod email_outbox;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod metrics;
 pub mod routes;
+pub mod session_state;
+pub mod session_store;
 pub mod startup;
 pub mod telemetry;
+pub mod utils;
 
 use actix_web::dev::Server;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
@@ -1,20 +1,40 @@
 use z2p::configuration::get_configuration;
-use z2p::telemetry::{get_subscriber, init_subscriber};
+use z2p::telemetry::{get_subscriber, init_subscriber, otlp_tracer_provider, set_redaction_policy};
 use z2p::startup::Application;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    //1. set telemetry
-    // env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-    let subscriber = get_subscriber("z2p".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
-
-    //2. read configuration
+    //1. read configuration
     //panic if we cannot read configuration
     let configuration = get_configuration().expect("Failed to read configuration");
 
+    //2. set telemetry, the exporter settings are part of the configuration
+    // env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    set_redaction_policy(
+        configuration.telemetry.redaction,
+        configuration.telemetry.redaction_key.as_ref(),
+    )
+    .map_err(std::io::Error::other)?;
+    let tracer_provider = configuration
+        .telemetry
+        .otlp
+        .as_ref()
+        .map(|otlp| otlp_tracer_provider("z2p", otlp))
+        .transpose()
+        .map_err(std::io::Error::other)?;
+    let subscriber = get_subscriber("z2p".into(), "info".into(), std::io::stdout, tracer_provider.as_ref());
+    init_subscriber(subscriber);
+
     //5. call run from startup
     let application = Application::build(configuration).await?;
+    //6. serve requests and deliver queued newsletter issues
+    let outcome = application.run_until_stopped().await;
 
-    Ok(())
+    //flush the spans still waiting in the batch, a failure to serve takes precedence over a failed flush
+    let flushed = match tracer_provider {
+        Some(tracer_provider) => tracer_provider.shutdown().map_err(std::io::Error::other),
+        None => Ok(()),
+    };
+    outcome?;
+    flushed
 }
@@ -0,0 +1,204 @@
+use crate::email_client::EmailError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::{Duration, Instant};
+
+//every application owns its registry, so several of them can live in one process (the tests do)
+pub struct Metrics {
+    registry: Registry,
+    http_requests_total: IntCounterVec,
+    http_request_duration_seconds: HistogramVec,
+    db_pool_connections: IntGauge,
+    db_pool_idle_connections: IntGauge,
+    db_pool_acquire_wait_seconds: Histogram,
+    email_sends_total: IntCounterVec,
+    email_send_duration_seconds: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+        let http_requests_total = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests served."),
+            &["method", "route", "status"],
+        )?;
+        let http_request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to answer HTTP requests.",
+            ),
+            &["method", "route"],
+        )?;
+        let db_pool_connections = IntGauge::new(
+            "db_pool_connections",
+            "Connections currently held by the Postgres pool, in use or idle.",
+        )?;
+        let db_pool_idle_connections = IntGauge::new(
+            "db_pool_idle_connections",
+            "Idle connections in the Postgres pool.",
+        )?;
+        let db_pool_acquire_wait_seconds = Histogram::with_opts(HistogramOpts::new(
+            "db_pool_acquire_wait_seconds",
+            "Time taken to get a connection from the Postgres pool and begin a transaction. \
+             Only transactions are timed, queries run straight on the pool are not.",
+        ))?;
+        let email_sends_total = IntCounterVec::new(
+            Opts::new("email_sends_total", "Emails handed to the email provider."),
+            &["provider", "outcome"],
+        )?;
+        let email_send_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "email_send_duration_seconds",
+                "Time taken by the email provider to accept or refuse an email.",
+            ),
+            &["provider", "outcome"],
+        )?;
+        registry.register(Box::new(http_requests_total.clone()))?;
+        registry.register(Box::new(http_request_duration_seconds.clone()))?;
+        registry.register(Box::new(db_pool_connections.clone()))?;
+        registry.register(Box::new(db_pool_idle_connections.clone()))?;
+        registry.register(Box::new(db_pool_acquire_wait_seconds.clone()))?;
+        registry.register(Box::new(email_sends_total.clone()))?;
+        registry.register(Box::new(email_send_duration_seconds.clone()))?;
+        Ok(Self {
+            registry,
+            http_requests_total,
+            http_request_duration_seconds,
+            db_pool_connections,
+            db_pool_idle_connections,
+            db_pool_acquire_wait_seconds,
+            email_sends_total,
+            email_send_duration_seconds,
+        })
+    }
+
+    pub fn observe_http_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.http_requests_total
+            .with_label_values(&[method, route, &status.to_string()])
+            .inc();
+        self.http_request_duration_seconds
+            .with_label_values(&[method, route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_email_send(
+        &self,
+        provider: &str,
+        outcome: Result<(), &EmailError>,
+        elapsed: Duration,
+    ) {
+        let outcome = email_outcome(outcome);
+        self.email_sends_total
+            .with_label_values(&[provider, outcome])
+            .inc();
+        self.email_send_duration_seconds
+            .with_label_values(&[provider, outcome])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    //read straight from the pool, so a scrape neither holds a connection nor waits for one
+    pub fn observe_pool(&self, pool: &PgPool) {
+        self.db_pool_connections.set(pool.size() as i64);
+        self.db_pool_idle_connections.set(pool.num_idle() as i64);
+    }
+
+    //the pool does not report waits itself, so they are timed where handlers and workers start
+    //their transactions; the BEGIN round trip is included, it is small next to a wait for a connection.
+    //Queries run straight on the pool (logins, the session store, the dashboard) are not timed
+    pub async fn begin_transaction(
+        &self,
+        pool: &PgPool,
+    ) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+        let start = Instant::now();
+        let transaction = pool.begin().await;
+        self.db_pool_acquire_wait_seconds
+            .observe(start.elapsed().as_secs_f64());
+        transaction
+    }
+
+    //Prometheus text exposition format
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+//routes are labelled with their pattern, unknown paths share one label to keep the series bounded
+pub async fn record_http_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+    //routing needs the request to itself, so it cannot be cloned before calling the next service
+    let method = req.method().clone();
+    let start = Instant::now();
+    let outcome = next.call(req).await;
+    if let Some(metrics) = metrics {
+        let (route, status) = match &outcome {
+            Ok(res) => (res.request().match_pattern(), res.status()),
+            Err(e) => (None, e.as_response_error().status_code()),
+        };
+        metrics.observe_http_request(
+            method.as_str(),
+            route.as_deref().unwrap_or("unmatched"),
+            status.as_u16(),
+            start.elapsed(),
+        );
+    }
+    outcome
+}
+
+//throttling is retried like an outage and shares its label; a provider that cannot be reached
+//is told apart from one that answers with an error
+fn email_outcome(outcome: Result<(), &EmailError>) -> &'static str {
+    match outcome {
+        Ok(()) => "success",
+        Err(EmailError::Rejected(_)) => "4xx",
+        Err(EmailError::Timeout(_)) => "timeout",
+        Err(EmailError::Transient(_)) => "5xx",
+        Err(EmailError::Unreachable(_)) => "connection_error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{email_outcome, Metrics};
+    use crate::email_client::EmailError;
+    use std::time::Duration;
+
+    #[test]
+    fn email_errors_are_labelled_by_outcome() {
+        assert_eq!(email_outcome(Ok(())), "success");
+        assert_eq!(email_outcome(Err(&EmailError::Rejected("".into()))), "4xx");
+        assert_eq!(email_outcome(Err(&EmailError::Transient("".into()))), "5xx");
+        assert_eq!(email_outcome(Err(&EmailError::Timeout("".into()))), "timeout");
+        assert_eq!(
+            email_outcome(Err(&EmailError::Unreachable("".into()))),
+            "connection_error"
+        );
+    }
+
+    #[test]
+    fn email_sends_are_counted_per_provider_and_outcome() {
+        let metrics = Metrics::new().unwrap();
+        metrics.observe_email_send("postmark", Ok(()), Duration::from_millis(20));
+        metrics.observe_email_send("postmark", Ok(()), Duration::from_millis(30));
+        metrics.observe_email_send(
+            "postmark",
+            Err(&EmailError::Timeout("".into())),
+            Duration::from_secs(10),
+        );
+
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"email_sends_total{outcome="success",provider="postmark"} 2"#));
+        assert!(text.contains(r#"email_sends_total{outcome="timeout",provider="postmark"} 1"#));
+    }
+}
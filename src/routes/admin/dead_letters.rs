@@ -0,0 +1,109 @@
+use crate::routes::admin::AdminError;
+use crate::telemetry::redact_email;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+pub struct DeadLetter {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_attempts: i16,
+    last_error: String,
+    failed_at: DateTime<Utc>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RequeueData {
+    newsletter_issue_id: Uuid,
+    //re-enqueue every dead letter of the issue when no address is given
+    subscriber_email: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct RequeueReceipt {
+    requeued: u64,
+}
+
+#[tracing::instrument(name = "List dead-lettered deliveries", skip(pool))]
+pub async fn list_dead_letters(pool: web::Data<PgPool>) -> Result<HttpResponse, AdminError> {
+    let dead_letters = get_dead_letters(&pool)
+        .await
+        .context("Failed to get the dead-lettered deliveries.")?;
+    Ok(HttpResponse::Ok().json(dead_letters))
+}
+
+#[tracing::instrument(
+    name = "Re-enqueue dead-lettered deliveries",
+    skip(body, pool),
+    fields(newsletter_issue_id = %body.newsletter_issue_id)
+)]
+pub async fn requeue_dead_letters(
+    body: web::Json<RequeueData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminError> {
+    let requeued = move_dead_letters_to_queue(
+        &pool,
+        body.newsletter_issue_id,
+        body.subscriber_email.as_deref(),
+    )
+    .await
+    .context("Failed to move the dead-lettered deliveries back to the queue.")?;
+    Ok(HttpResponse::Ok().json(RequeueReceipt { requeued }))
+}
+
+#[tracing::instrument(name = "Get dead-lettered deliveries", skip(pool))]
+async fn get_dead_letters(pool: &PgPool) -> Result<Vec<DeadLetter>, sqlx::Error> {
+    sqlx::query_as!(
+        DeadLetter,
+        r#"
+        SELECT newsletter_issue_id, subscriber_email, n_attempts, last_error, failed_at
+        FROM issue_delivery_dead_letters
+        ORDER BY failed_at DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+#[tracing::instrument(
+    name = "Move dead-lettered deliveries back to the queue",
+    skip(pool, subscriber_email),
+    fields(subscriber_email = subscriber_email.map(|e| tracing::field::display(redact_email(e))))
+)]
+async fn move_dead_letters_to_queue(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    subscriber_email: Option<&str>,
+) -> Result<u64, sqlx::Error> {
+    //a single statement, so a delivery is never in both tables or in neither
+    let result = sqlx::query!(
+        r#"
+        WITH moved AS (
+            DELETE FROM issue_delivery_dead_letters
+            WHERE
+                newsletter_issue_id = $1 AND
+                ($2::TEXT IS NULL OR subscriber_email = $2)
+            RETURNING newsletter_issue_id, subscriber_email
+        )
+        INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+        SELECT newsletter_issue_id, subscriber_email FROM moved
+        ON CONFLICT DO NOTHING
+        "#,
+        newsletter_issue_id,
+        subscriber_email
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,13 @@
+use crate::routes::admin::AdminError;
+use crate::session_state::TypedSession;
+use crate::utils::see_other;
+use actix_web::HttpResponse;
+use anyhow::Context;
+
+pub async fn log_out(session: TypedSession) -> Result<HttpResponse, AdminError> {
+    session.log_out();
+    session
+        .insert_flash("You have successfully logged out.")
+        .context("Failed to store the flash message in the session.")?;
+    Ok(see_other("/login"))
+}
@@ -0,0 +1,38 @@
+pub(crate) mod dashboard;
+mod dead_letters;
+mod logout;
+mod password;
+
+pub use dashboard::*;
+pub use dead_letters::*;
+pub use logout::*;
+pub use password::*;
+
+use crate::utils::error_chain_fmt;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+
+//admins only ever see a 500 when something breaks, the cause is logged by the request's root span
+#[derive(thiserror::Error)]
+pub enum AdminError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).finish()
+    }
+}
@@ -0,0 +1,56 @@
+use crate::authentication::{self, validate_credentials, validate_new_password, AuthError, Credentials, UserId};
+use crate::routes::admin::dashboard::get_username;
+use crate::routes::admin::AdminError;
+use crate::session_state::TypedSession;
+use crate::utils::see_other;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use secrecy::Secret;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(name = "Change admin password", skip(form, pool, session), fields(user_id = %*user_id))]
+pub async fn change_password(
+    form: web::Form<FormData>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, AdminError> {
+    let user_id = user_id.into_inner();
+    if let Err(message) = validate_new_password(&form.new_password, &form.new_password_check) {
+        return flash_and_redirect(&session, &message);
+    }
+    let username = get_username(*user_id, &pool)
+        .await
+        .context("Failed to get the username.")?;
+    let credentials = Credentials {
+        username,
+        password: form.0.current_password,
+    };
+    match validate_credentials(credentials, &pool).await {
+        Ok(_) => {}
+        Err(AuthError::InvalidCredentials(_)) => {
+            return flash_and_redirect(&session, "The current password is incorrect.");
+        }
+        Err(AuthError::UnexpectedError(e)) => {
+            return Err(e.context("Failed to validate the current password.").into());
+        }
+    }
+    authentication::change_password(*user_id, form.0.new_password, &pool)
+        .await
+        .context("Failed to change the password.")?;
+    flash_and_redirect(&session, "Your password has been changed.")
+}
+
+fn flash_and_redirect(session: &TypedSession, message: &str) -> Result<HttpResponse, AdminError> {
+    session
+        .insert_flash(message)
+        .context("Failed to store the flash message in the session.")?;
+    Ok(see_other("/admin/password"))
+}
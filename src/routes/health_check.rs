@@ -1,5 +1,134 @@
+use crate::email_client::EmailSender;
 use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
 
 pub async fn check_health() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub struct HealthState {
+    started_at: Instant,
+    timeout: Duration,
+    probe_email_provider: bool,
+}
+
+impl HealthState {
+    pub fn new(timeout: Duration, probe_email_provider: bool) -> Self {
+        Self {
+            started_at: Instant::now(),
+            timeout,
+            probe_email_provider,
+        }
+    }
+
+    fn uptime_seconds(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Liveness {
+    status: &'static str,
+    version: &'static str,
+    uptime_seconds: u64,
+}
+
+#[derive(serde::Serialize)]
+struct Readiness {
+    status: &'static str,
+    version: &'static str,
+    uptime_seconds: u64,
+    checks: Checks,
+}
+
+#[derive(serde::Serialize)]
+struct Checks {
+    database: DependencyCheck,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_provider: Option<DependencyCheck>,
+}
+
+#[derive(serde::Serialize)]
+struct DependencyCheck {
+    status: &'static str,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+impl DependencyCheck {
+    fn is_up(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+//the process is running and serving requests, nothing else is checked
+pub async fn health_live(state: web::Data<HealthState>) -> HttpResponse {
+    HttpResponse::Ok().json(Liveness {
+        status: "ok",
+        version: VERSION,
+        uptime_seconds: state.uptime_seconds(),
+    })
+}
+
+//only the database is critical: confirmation emails wait in the outbox while the provider is down
+pub async fn health_ready(
+    pool: web::Data<PgPool>,
+    email_client: web::Data<dyn EmailSender>,
+    state: web::Data<HealthState>,
+) -> HttpResponse {
+    let database = check_dependency("database", state.timeout, async {
+        sqlx::query("SELECT 1").execute(pool.get_ref()).await.map(|_| ())
+    })
+    .await;
+    let email_provider = match state.probe_email_provider {
+        true => Some(check_dependency("email provider", state.timeout, email_client.probe()).await),
+        false => None,
+    };
+    let (status, mut response) = if !database.is_up() {
+        ("down", HttpResponse::ServiceUnavailable())
+    } else if email_provider.as_ref().is_some_and(|check| !check.is_up()) {
+        ("degraded", HttpResponse::Ok())
+    } else {
+        ("ok", HttpResponse::Ok())
+    };
+    response.json(Readiness {
+        status,
+        version: VERSION,
+        uptime_seconds: state.uptime_seconds(),
+        checks: Checks {
+            database,
+            email_provider,
+        },
+    })
+}
+
+//the reason only goes to the logs, the probe is usually reachable from outside
+async fn check_dependency<E: Display>(
+    name: &str,
+    timeout: Duration,
+    probe: impl Future<Output = Result<(), E>>,
+) -> DependencyCheck {
+    let started_at = Instant::now();
+    let error = match tokio::time::timeout(timeout, probe).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => {
+            tracing::warn!("Readiness check for the {} failed: {}", name, e);
+            Some("unavailable")
+        }
+        Err(_) => {
+            tracing::warn!("Readiness check for the {} timed out after {:?}", name, timeout);
+            Some("timed out")
+        }
+    };
+    DependencyCheck {
+        status: if error.is_none() { "up" } else { "down" },
+        latency_ms: started_at.elapsed().as_millis() as u64,
+        error,
+    }
+}
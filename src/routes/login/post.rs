@@ -0,0 +1,75 @@
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::session_state::TypedSession;
+use crate::utils::{error_chain_fmt, see_other};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use secrecy::Secret;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    username: String,
+    password: Secret<String>,
+}
+
+#[tracing::instrument(
+    skip(form, pool, session),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn login(
+    form: web::Form<FormData>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+) -> Result<HttpResponse, LoginError> {
+    let credentials = Credentials {
+        username: form.0.username,
+        password: form.0.password,
+    };
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+            session.renew();
+            session
+                .insert_user_id(user_id)
+                .context("Failed to store the user id in the session.")?;
+            Ok(see_other("/admin/dashboard"))
+        }
+        //a wrong username or password is an expected outcome, the user is sent back to the form
+        Err(AuthError::InvalidCredentials(e)) => {
+            tracing::warn!("Rejected a login attempt: {:?}", e);
+            session
+                .insert_flash("Authentication failed")
+                .context("Failed to store the flash message in the session.")?;
+            Ok(see_other("/login"))
+        }
+        Err(AuthError::UnexpectedError(e)) => {
+            Err(LoginError::UnexpectedError(e.context("Failed to validate credentials.")))
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum LoginError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for LoginError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            LoginError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).finish()
+    }
+}
@@ -0,0 +1,17 @@
+use crate::metrics::Metrics;
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+
+//scraped by Prometheus; the pool gauges are sampled now, everything else is counted as it happens
+pub async fn metrics(
+    pool: web::Data<PgPool>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, actix_web::Error> {
+    metrics.observe_pool(&pool);
+    let body = metrics
+        .encode()
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(body))
+}
@@ -1,7 +1,18 @@
+pub mod admin;
 mod health_check;
+mod login;
+mod metrics;
+mod newsletters;
+mod password_reset;
 mod subscriptions;
 pub(crate) mod subscriptions_confirm;
+mod subscriptions_unsubscribe;
 
 pub use health_check::*;
+pub use login::*;
+pub use metrics::*;
+pub use newsletters::*;
+pub use password_reset::*;
 pub use subscriptions::*;
-pub use subscriptions_confirm::*;
\ No newline at end of file
+pub use subscriptions_confirm::*;
+pub use subscriptions_unsubscribe::*;
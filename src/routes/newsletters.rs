@@ -0,0 +1,183 @@
+use crate::authentication::{authenticate_basic, AuthError};
+use crate::idempotency::{
+    begin_processing, complete_processing, idempotency_key_from_request, request_hash,
+    IdempotencyRetention, NextAction,
+};
+use crate::metrics::Metrics;
+use crate::utils::error_chain_fmt;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+    //alternative to the `Idempotency-Key` header
+    idempotency_key: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Content {
+    html: String,
+    text: String,
+}
+
+//the issue is only queued here; the delivery worker sends it in the background
+#[derive(serde::Serialize)]
+pub struct PublishReceipt {
+    newsletter_issue_id: Uuid,
+    enqueued: u64,
+}
+
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(body, pool, metrics, request, retention),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn publish_newsletter(
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    metrics: web::Data<Metrics>,
+    request: HttpRequest,
+    retention: web::Data<IdempotencyRetention>,
+) -> Result<HttpResponse, PublishError> {
+    let user_id = authenticate_basic(&request, &pool).await?;
+    let BodyData {
+        title,
+        content,
+        idempotency_key,
+    } = body.0;
+    let idempotency_key = idempotency_key_from_request(&request, idempotency_key)
+        .map_err(PublishError::InvalidIdempotencyKey)?;
+    let request_hash = request_hash(&[&title, &content.text, &content.html]);
+    //a retried or double-clicked publish gets the first response back instead of a second issue
+    let mut transaction = match begin_processing(&pool, &metrics, idempotency_key.as_ref(), &request_hash, user_id, **retention)
+        .await
+        .context("Failed to start processing the request.")?
+    {
+        NextAction::StartProcessing(transaction) => transaction,
+        NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+    };
+    let issue_id = insert_newsletter_issue(&mut transaction, &title, &content.text, &content.html)
+        .await
+        .context("Failed to store newsletter issue details.")?;
+    let enqueued = enqueue_delivery_tasks(&mut transaction, issue_id)
+        .await
+        .context("Failed to enqueue delivery tasks.")?;
+    let response = HttpResponse::Accepted().json(PublishReceipt {
+        newsletter_issue_id: issue_id,
+        enqueued,
+    });
+    let response = complete_processing(transaction, idempotency_key.as_ref(), user_id, response)
+        .await
+        .context("Failed to save the response.")?;
+    Ok(response)
+}
+
+#[derive(thiserror::Error)]
+pub enum PublishError {
+    #[error("Authentication failed.")]
+    AuthError(#[source] anyhow::Error),
+    #[error("Invalid idempotency key: {0}")]
+    InvalidIdempotencyKey(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl From<AuthError> for PublishError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::InvalidCredentials(e) => PublishError::AuthError(e),
+            AuthError::UnexpectedError(e) => PublishError::UnexpectedError(e),
+        }
+    }
+}
+
+impl ResponseError for PublishError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PublishError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            PublishError::InvalidIdempotencyKey(_) => StatusCode::BAD_REQUEST,
+            PublishError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    //clients are told which scheme to authenticate with
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let PublishError::AuthError(_) = self {
+            response.insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="publish""#));
+        }
+        response.finish()
+    }
+}
+
+#[tracing::instrument(name = "Save newsletter issue details in the database", skip_all)]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    title: &str,
+    text_content: &str,
+    html_content: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues (
+            newsletter_issue_id,
+            title,
+            text_content,
+            html_content,
+            published_at
+        )
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        newsletter_issue_id,
+        title,
+        text_content,
+        html_content,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(newsletter_issue_id)
+}
+
+#[tracing::instrument(name = "Enqueue delivery tasks for confirmed subscribers", skip(transaction))]
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (
+            newsletter_issue_id,
+            subscriber_email
+        )
+        SELECT $1, email
+        FROM subscriptions
+        WHERE status = 'confirmed'
+        "#,
+        newsletter_issue_id,
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected())
+}
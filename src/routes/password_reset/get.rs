@@ -0,0 +1,88 @@
+use crate::session_state::TypedSession;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    token: String,
+}
+
+fn flash_html(session: &TypedSession) -> String {
+    match session.take_flash() {
+        Some(message) => format!("<p><i>{}</i></p>", htmlescape::encode_minimal(&message)),
+        None => String::new(),
+    }
+}
+
+//ask for the address the reset link should be sent to
+pub async fn password_reset_form(session: TypedSession) -> HttpResponse {
+    let msg_html = flash_html(&session);
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Reset password</title>
+</head>
+<body>
+    {msg_html}
+    <form action="/password_reset" method="post">
+        <label>Email
+            <input
+                type="email"
+                placeholder="Enter the email address of your account"
+                name="email"
+            >
+        </label>
+        <button type="submit">Send reset link</button>
+    </form>
+    <p><a href="/login">&lt;- Back to login</a></p>
+</body>
+</html>"#,
+        ))
+}
+
+//landing page of the emailed link, the token travels on as a hidden field
+pub async fn reset_password_form(
+    parameters: web::Query<Parameters>,
+    session: TypedSession,
+) -> HttpResponse {
+    let msg_html = flash_html(&session);
+    let token = htmlescape::encode_attribute(&parameters.token);
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Choose a new password</title>
+</head>
+<body>
+    {msg_html}
+    <form action="/password_reset/confirm" method="post">
+        <input type="hidden" name="token" value="{token}">
+        <label>New password
+            <input
+                type="password"
+                placeholder="Enter new password"
+                name="new_password"
+            >
+        </label>
+        <br>
+        <label>Confirm new password
+            <input
+                type="password"
+                placeholder="Type the new password again"
+                name="new_password_check"
+            >
+        </label>
+        <br>
+        <button type="submit">Reset password</button>
+    </form>
+</body>
+</html>"#,
+        ))
+}
@@ -0,0 +1,283 @@
+use crate::authentication::{hash_password, validate_new_password};
+use crate::domain::SubscriberEmail;
+use crate::email_outbox::enqueue_email;
+use crate::metrics::Metrics;
+use crate::session_state::TypedSession;
+use crate::startup::ApplicationBaseUrl;
+use crate::utils::{error_chain_fmt, see_other};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+//how long an emailed reset link stays valid
+const RESET_TOKEN_TTL_MINUTES: i64 = 60;
+
+#[derive(serde::Deserialize)]
+pub struct RequestFormData {
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetFormData {
+    token: String,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+}
+
+#[tracing::instrument(name = "Request a password reset", skip(form, pool, metrics, base_url, session))]
+pub async fn request_password_reset(
+    form: web::Form<RequestFormData>,
+    pool: web::Data<PgPool>,
+    metrics: web::Data<Metrics>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    session: TypedSession,
+) -> Result<HttpResponse, PasswordResetError> {
+    let user_id = get_user_id_by_email(&pool, &form.email)
+        .await
+        .context("Failed to look up the user by email address.")?;
+    //the email is left to the outbox dispatcher: waiting for the provider here would make
+    //known addresses answer measurably slower than unknown ones
+    if let Some(user_id) = user_id {
+        match SubscriberEmail::parse(form.0.email) {
+            Ok(email) => {
+                let token = generate_reset_token();
+                let mut transaction = metrics
+                    .begin_transaction(&pool)
+                    .await
+                    .context("Failed to acquire a Postgres connection from the pool.")?;
+                store_reset_token(&mut transaction, user_id, &token)
+                    .await
+                    .context("Failed to store the password reset token.")?;
+                enqueue_reset_email(&mut transaction, &email, &base_url.0, &token)
+                    .await
+                    .context("Failed to queue the password reset email.")?;
+                transaction
+                    .commit()
+                    .await
+                    .context("Failed to commit SQL transaction to store the password reset token.")?;
+            }
+            //only logged, the response must look the same whether the account exists or not
+            Err(e) => tracing::error!("The stored admin email is invalid: {}", e),
+        }
+    }
+    flash_and_redirect(
+        &session,
+        "If an account is registered with that address, you will receive a link to reset your password.",
+        "/password_reset",
+    )
+}
+
+#[tracing::instrument(name = "Reset a password", skip(form, pool, metrics, session))]
+pub async fn reset_password(
+    form: web::Form<ResetFormData>,
+    pool: web::Data<PgPool>,
+    metrics: web::Data<Metrics>,
+    session: TypedSession,
+) -> Result<HttpResponse, PasswordResetError> {
+    if let Err(message) = validate_new_password(&form.new_password, &form.new_password_check) {
+        //the token is not consumed, let the user try again with the same link
+        //the token comes from the client, it is encoded before going back into a header
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", &form.token)
+            .finish();
+        let retry_url = format!("/password_reset/confirm?{}", query);
+        return flash_and_redirect(&session, &message, &retry_url);
+    }
+    let password_hash = hash_password(form.0.new_password)
+        .await
+        .context("Failed to hash the new password.")?;
+    let mut transaction = metrics
+        .begin_transaction(&pool)
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let user_id = match consume_reset_token(&mut transaction, &form.0.token)
+        .await
+        .context("Failed to consume the password reset token.")?
+    {
+        Some(user_id) => user_id,
+        None => {
+            return flash_and_redirect(
+                &session,
+                "This password reset link is invalid or has expired. Please request a new one.",
+                "/password_reset",
+            )
+        }
+    };
+    store_password_hash(&mut transaction, user_id, password_hash)
+        .await
+        .context("Failed to store the new password hash.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store the new password.")?;
+    flash_and_redirect(
+        &session,
+        "Your password has been reset. You can now log in.",
+        "/login",
+    )
+}
+
+#[derive(thiserror::Error)]
+pub enum PasswordResetError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PasswordResetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PasswordResetError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PasswordResetError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).finish()
+    }
+}
+
+fn flash_and_redirect(
+    session: &TypedSession,
+    message: &str,
+    location: &str,
+) -> Result<HttpResponse, PasswordResetError> {
+    session
+        .insert_flash(message)
+        .context("Failed to store the flash message in the session.")?;
+    Ok(see_other(location))
+}
+
+fn generate_reset_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect()
+}
+
+//a leaked database must not hand out working reset links
+fn hash_reset_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[tracing::instrument(name = "Get the user_id from an email address", skip(pool, email))]
+async fn get_user_id_by_email(pool: &PgPool, email: &str) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(r#"SELECT user_id FROM users WHERE email = $1"#, email)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(row.map(|r| r.user_id))
+}
+
+#[tracing::instrument(name = "Store password reset token in the database", skip(transaction, token))]
+async fn store_reset_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO password_reset_tokens (token_hash, user_id, expires_at)
+        VALUES ($1, $2, $3)
+        "#,
+        hash_reset_token(token),
+        user_id,
+        Utc::now() + chrono::Duration::minutes(RESET_TOKEN_TTL_MINUTES)
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Queue password reset email", skip(transaction, recipient, base_url, token))]
+async fn enqueue_reset_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    base_url: &str,
+    token: &str,
+) -> Result<(), sqlx::Error> {
+    let reset_link = format!("{}/password_reset/confirm?token={}", base_url, token);
+    let plain_body = format!(
+        "Somebody asked to reset your password.\nVisit {} to choose a new one. The link expires in {} minutes.",
+        reset_link, RESET_TOKEN_TTL_MINUTES
+    );
+    let html_body = format!(
+        "Somebody asked to reset your password.<br />Click <a href=\"{}\">here</a> to choose a new one. The link expires in {} minutes.",
+        reset_link, RESET_TOKEN_TTL_MINUTES
+    );
+    enqueue_email(transaction, recipient, "Reset your password", &html_body, &plain_body).await?;
+    Ok(())
+}
+
+//deleting the token is what makes it single-use; expired tokens are deleted as well
+#[tracing::instrument(name = "Consume password reset token", skip(transaction, token))]
+async fn consume_reset_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        DELETE FROM password_reset_tokens
+        WHERE token_hash = $1
+        RETURNING user_id, expires_at
+        "#,
+        hash_reset_token(token)
+    )
+    .fetch_optional(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(row.filter(|r| r.expires_at > Utc::now()).map(|r| r.user_id))
+}
+
+#[tracing::instrument(name = "Store the new password hash", skip(transaction, password_hash))]
+async fn store_password_hash(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    password_hash: Secret<String>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE users SET password_hash = $1 WHERE user_id = $2"#,
+        password_hash.expose_secret(),
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    //any other outstanding link of this user is now stale
+    sqlx::query!(
+        r#"DELETE FROM password_reset_tokens WHERE user_id = $1"#,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
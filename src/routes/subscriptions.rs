@@ -1,15 +1,27 @@
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
-use actix_web::{web, HttpResponse};
+use crate::domain::{NewSubscriber, NewSubscriberError, SubscriberEmail, SubscriptionToken};
+use crate::utils::error_chain_fmt;
+use crate::telemetry::{redact_email, redact_name};
+use actix_web::dev::Payload;
+use actix_web::http::header::{Accept, Header, Quality};
+use actix_web::http::StatusCode;
+use actix_web::{mime, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
 use sqlx::{Postgres, Transaction};
 use chrono::Utc;
 use sqlx;
 use sqlx::PgPool;
 use tracing::Instrument;
 use uuid::Uuid;
-use crate::email_client::EmailClient;
+use crate::email_outbox::enqueue_email;
+use crate::metrics::Metrics;
+use crate::idempotency::{
+    begin_processing, complete_processing, idempotency_key_from_request, request_hash,
+    IdempotencyRetention, NextAction, ANONYMOUS_USER_ID,
+};
 use crate::startup::ApplicationBaseUrl;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::pin::Pin;
 
 
 //form data is basically described by me; tailored to my application
@@ -17,96 +29,294 @@ use rand::{thread_rng, Rng};
 pub struct FormData {
     pub email: String,
     pub name: String,
+    //hidden form field, alternative to the `Idempotency-Key` header
+    pub idempotency_key: Option<String>,
+}
+
+//the signup page posts a form while the SPA and the mobile app send JSON; the Content-Type decides
+pub struct SubscribeBody(pub FormData);
+
+impl FromRequest for SubscribeBody {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let is_json = req
+            .mime_type()
+            .ok()
+            .flatten()
+            .is_some_and(|mime| mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON));
+        if is_json {
+            let json = web::Json::<FormData>::from_request(req, payload);
+            Box::pin(async move { Ok(Self(json.await?.into_inner())) })
+        } else {
+            let form = web::Form::<FormData>::from_request(req, payload);
+            Box::pin(async move { Ok(Self(form.await?.into_inner())) })
+        }
+    }
+}
+
+//what JSON clients get back; it reads the same whether or not the address was already on the list
+#[derive(serde::Serialize)]
+struct SubscribeReceipt {
+    message: &'static str,
+}
+
+//the client's first choice decides, so browsers listing text/html first keep the empty 200;
+//`application/*` and `*/*` clients get JSON, `q=0` marks a type as not acceptable at all
+fn accepts_json(request: &HttpRequest) -> bool {
+    let Ok(accept) = Accept::parse(request) else {
+        return false;
+    };
+    let refuses_json = accept
+        .iter()
+        .any(|item| item.item == mime::APPLICATION_JSON && item.quality == Quality::ZERO);
+    if refuses_json {
+        return false;
+    }
+    let acceptable = Accept(
+        accept
+            .iter()
+            .filter(|item| item.quality > Quality::ZERO)
+            .cloned()
+            .collect(),
+    );
+    acceptable.ranked().first().is_some_and(|preferred| {
+        preferred.type_() == mime::STAR
+            || (preferred.type_() == mime::APPLICATION
+                && (preferred.subtype() == mime::JSON || preferred.subtype() == mime::STAR))
+    })
+}
+
+//a pending subscriber gets a fresh confirmation email at most once per interval
+pub struct ConfirmationResendInterval(pub chrono::Duration);
+
+//how long a confirmation link stays valid
+pub struct SubscriptionTokenTtl(pub chrono::Duration);
+
+//everything `subscribe` reads from the configuration, built once in `startup::run`
+pub struct SubscribeSettings {
+    pub base_url: ApplicationBaseUrl,
+    pub retention: IdempotencyRetention,
+    pub resend_interval: ConfirmationResendInterval,
+    pub token_ttl: SubscriptionTokenTtl,
 }
 
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(body, pool, metrics, request, settings),
     fields(
-        subscriber_email = %form.email,
-        subscriber_name = %form.name
+        subscriber_email = %redact_email(&body.0.email),
+        subscriber_name = %redact_name(&body.0.name)
     )
 )]
-pub async fn subscribe(form: web::Form<FormData>, pool: web::Data<PgPool>, email_client: web::Data<EmailClient>, base_url: web::Data<ApplicationBaseUrl>) -> HttpResponse {
+pub async fn subscribe(body: SubscribeBody, pool: web::Data<PgPool>, metrics: web::Data<Metrics>, request: HttpRequest, settings: web::Data<SubscribeSettings>) -> Result<HttpResponse, SubscribeError> {
+    let form = body.0;
+    let idempotency_key = idempotency_key_from_request(&request, form.idempotency_key.clone())
+        .map_err(SubscribeError::InvalidIdempotencyKey)?;
+    let request_hash = request_hash(&[&form.email, &form.name]);
     //try_into works because TryFrom was implemented for new_subscriber which converts form to a New Subscriber type
-    let new_subscriber = match form.0.try_into() {
-        //we use try_into here as we have implemented try_from
-        Ok(form) => form,
-        Err(_) => return HttpResponse::BadRequest().finish(),
+    let new_subscriber: NewSubscriber = form.try_into()?;
+    //a replayed key returns the first response without touching the database again
+    let mut transaction = match begin_processing(&pool, &metrics, idempotency_key.as_ref(), &request_hash, ANONYMOUS_USER_ID, settings.retention)
+        .await
+        .context("Failed to start processing the request.")?
+    {
+        NextAction::StartProcessing(transaction) => transaction,
+        NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
     };
-    let mut transaction = match pool.begin().await {
-        Ok(transaction) => transaction,
-        Err(_) => return HttpResponse::InternalServerError().finish()
+    //submitting the form again is not an error: pending subscribers get a new link,
+    //everyone else gets the same 200 so the response does not reveal who is on the list
+    let subscriber_id = match insert_subscriber(&mut transaction, &new_subscriber)
+        .await
+        .context("Failed to insert new subscriber in the database.")?
+    {
+        Some(subscriber_id) => Some(subscriber_id),
+        None => prepare_resend(&mut transaction, &new_subscriber.email, settings.resend_interval.0)
+            .await
+            .context("Failed to look up the existing subscriber.")?,
     };
-    let subscriber_id = match insert_subscriber(&mut transaction, &new_subscriber).await {
-        Ok(subsciber_id) => subsciber_id,
-        Err(_) => return HttpResponse::InternalServerError().finish()
+    if let Some(subscriber_id) = subscriber_id {
+        let subscription_token = SubscriptionToken::generate();
+        store_token(&mut transaction, subscriber_id, &subscription_token, settings.token_ttl.0)
+            .await
+            .context("Failed to store the confirmation token for a new subscriber.")?;
+        //the email is committed together with the subscriber and sent by the outbox dispatcher,
+        //so an email provider outage no longer fails the request
+        enqueue_confirmation_email(&mut transaction, &new_subscriber, &settings.base_url.0, &subscription_token, settings.token_ttl.0)
+            .await
+            .context("Failed to queue the confirmation email.")?;
+    }
+    let response = if accepts_json(&request) {
+        HttpResponse::Ok().json(SubscribeReceipt {
+            message: "Check your inbox to confirm your subscription.",
+        })
+    } else {
+        HttpResponse::Ok().finish()
     };
-    let subscription_token = generate_subscription_token();
-    if store_token(&mut transaction, subscriber_id, &subscription_token).await.is_err() {
-        return HttpResponse::InternalServerError().finish();
+    let response = complete_processing(transaction, idempotency_key.as_ref(), ANONYMOUS_USER_ID, response)
+        .await
+        .context("Failed to save the response.")?;
+    Ok(response)
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error(transparent)]
+    ValidationError(#[from] NewSubscriberError),
+    #[error("Invalid idempotency key: {0}")]
+    InvalidIdempotencyKey(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+//RFC 7807 body describing why the submitted details were rejected
+#[derive(serde::Serialize)]
+struct ValidationProblem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    errors: Vec<InvalidField>,
+}
+
+#[derive(serde::Serialize)]
+struct InvalidField {
+    field: &'static str,
+    code: &'static str,
+    detail: String,
+}
+
+impl From<&NewSubscriberError> for ValidationProblem {
+    fn from(e: &NewSubscriberError) -> Self {
+        Self {
+            problem_type: "about:blank",
+            title: "Bad Request",
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            detail: e.to_string(),
+            errors: e
+                .violations()
+                .into_iter()
+                .map(|v| InvalidField {
+                    field: v.field,
+                    code: v.code,
+                    detail: v.message,
+                })
+                .collect(),
+        }
     }
-    if transaction.commit().await.is_err() {
-        return HttpResponse::InternalServerError().finish();
+}
+
+//the cause is logged by the request's root span; apart from invalid fields, clients only get the status code
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_) | SubscribeError::InvalidIdempotencyKey(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
-    //sending a useless email to the new subscriber; ignore email delivery errors for now.
-    if send_confirmation_email(&email_client, new_subscriber, &base_url.0, &subscription_token).await.is_err() {
-        return HttpResponse::InternalServerError().finish();
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SubscribeError::ValidationError(e) => HttpResponse::build(self.status_code())
+                .content_type("application/problem+json")
+                .json(ValidationProblem::from(e)),
+            _ => HttpResponse::build(self.status_code()).finish(),
+        }
     }
-    HttpResponse::Ok().finish()
 }
 
 #[tracing::instrument(name = "Saving new subscriber details in the database", skip(new_subscriber, transaction))]
-pub async fn insert_subscriber(transaction: &mut Transaction<'_, Postgres>, new_subscriber: &NewSubscriber) -> Result<Uuid, sqlx::Error> {
+//returns None if the email is already in the table
+pub async fn insert_subscriber(transaction: &mut Transaction<'_, Postgres>, new_subscriber: &NewSubscriber) -> Result<Option<Uuid>, sqlx::Error> {
     let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
+    let inserted = sqlx::query!(
         r#"
-        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-        VALUES ($1, $2, $3, $4, 'pending_confirmation')
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status, unsubscribe_token, confirmation_sent_at)
+        VALUES ($1, $2, $3, $4, 'pending_confirmation', $5, now())
+        ON CONFLICT (email) DO NOTHING
         "#,
         subscriber_id,
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
         Utc::now(),
+        //same shape as the tokens backfilled by the migration
+        Uuid::new_v4().simple().to_string(),
     )
     .execute(transaction)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-
-    Ok(subscriber_id)
-}
-#[tracing::instrument(name = "Send confirmation email to a new subscriber", skip(email_client, new_subscriber, base_url))]
-pub async fn send_confirmation_email(email_client: &EmailClient, new_subscriber: NewSubscriber, base_url: &str, subscription_token: &str) -> Result<(), reqwest::Error> {
-    let confirmation_link = format!("{}/subscriptions/confirm?subscription_token={}", base_url, subscription_token);
-    let plain_body = &format!("Welcome to our newsletter! \nVisit {} to confirm your subscription.", confirmation_link);
-    let html_body = &format!("Welcome to our newsletter!<br /> Click <a href=\"{}\">here</a> to confirm your subscription.", confirmation_link);
-    email_client.send_email(
-        new_subscriber.email,
+    .await?;
+
+    Ok((inserted.rows_affected() == 1).then_some(subscriber_id))
+}
+
+//decides whether an existing subscriber gets a new confirmation email;
+//their old links are dropped so only the latest one works
+#[tracing::instrument(name = "Prepare to re-send a confirmation email", skip(transaction, email))]
+pub async fn prepare_resend(transaction: &mut Transaction<'_, Postgres>, email: &SubscriberEmail, resend_interval: chrono::Duration) -> Result<Option<Uuid>, sqlx::Error> {
+    let subscriber = sqlx::query!(
+        r#"SELECT id, status, confirmation_sent_at FROM subscriptions WHERE email = $1 FOR UPDATE"#,
+        email.as_ref()
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    if subscriber.status == "confirmed" {
+        tracing::info!("The subscriber is already confirmed, not sending another email");
+        return Ok(None);
+    }
+    if subscriber.confirmation_sent_at.is_some_and(|sent_at| Utc::now() - sent_at < resend_interval) {
+        tracing::info!("A confirmation email was sent recently, not sending another one");
+        return Ok(None);
+    }
+    //people who unsubscribed can rejoin, but only by confirming again
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'pending_confirmation', confirmation_sent_at = now() WHERE id = $1"#,
+        subscriber.id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(r#"DELETE FROM subscriptions_tokens WHERE subscriber_id = $1"#, subscriber.id)
+        .execute(&mut *transaction)
+        .await?;
+    Ok(Some(subscriber.id))
+}
+#[tracing::instrument(name = "Queue confirmation email for a new subscriber", skip(transaction, new_subscriber, base_url, subscription_token))]
+pub async fn enqueue_confirmation_email(transaction: &mut Transaction<'_, Postgres>, new_subscriber: &NewSubscriber, base_url: &str, subscription_token: &SubscriptionToken, token_ttl: chrono::Duration) -> Result<(), sqlx::Error> {
+    let confirmation_link = format!("{}/subscriptions/confirm?subscription_token={}", base_url, subscription_token.as_ref());
+    let plain_body = &format!("Welcome to our newsletter! \nVisit {} to confirm your subscription. The link expires in {} hours.", confirmation_link, token_ttl.num_hours());
+    let html_body = &format!("Welcome to our newsletter!<br /> Click <a href=\"{}\">here</a> to confirm your subscription. The link expires in {} hours.", confirmation_link, token_ttl.num_hours());
+    enqueue_email(
+        transaction,
+        &new_subscriber.email,
         "Welcome!",
-        &html_body,
-        &plain_body
-    ).await
+        html_body,
+        plain_body
+    ).await?;
+    Ok(())
 }
 
-fn generate_subscription_token() -> String {
-    let mut rng = thread_rng();
-    std::iter::repeat_with(|| rng.sample(Alphanumeric))
-        .map(char::from)
-        .take(25)
-        .collect()
+//a leaked database must not hand out working confirmation links
+pub fn hash_subscription_token(subscription_token: &SubscriptionToken) -> String {
+    hex::encode(Sha256::digest(subscription_token.as_ref().as_bytes()))
 }
 
 #[tracing::instrument(name="Store subsciption token in the database", skip(transaction, subscription_token))]
-pub async fn store_token(transaction:&mut Transaction<'_, Postgres>, subscriber_id:Uuid, subscription_token:&str) -> Result<(), sqlx::Error> {
-    sqlx::query!(r#"INSERT INTO subscriptions_tokens (subscription_token, subscriber_id) VALUES ($1, $2)"#, subscription_token, subscriber_id)
+pub async fn store_token(transaction:&mut Transaction<'_, Postgres>, subscriber_id:Uuid, subscription_token:&SubscriptionToken, token_ttl: chrono::Duration) -> Result<(), sqlx::Error> {
+    let issued_at = Utc::now();
+    sqlx::query!(
+        r#"INSERT INTO subscriptions_tokens (token_hash, subscriber_id, issued_at, expires_at) VALUES ($1, $2, $3, $4)"#,
+        hash_subscription_token(subscription_token),
+        subscriber_id,
+        issued_at,
+        issued_at + token_ttl
+    )
         .execute(transaction)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to execute query: {:?}", e);
-            e
-        })?;
+        .await?;
     Ok(())
 }
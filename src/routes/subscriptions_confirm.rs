@@ -1,52 +1,177 @@
-use actix_web::{HttpResponse, web};
-use tracing::Instrument;
-use sqlx::PgPool;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError, web};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use crate::domain::{SubscriptionToken, SubscriptionTokenError};
+use crate::metrics::Metrics;
+use crate::routes::hash_subscription_token;
+use crate::utils::error_chain_fmt;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct Paramerters {
     subscription_token: String
 }
-#[tracing::instrument(name="Confirm a pending subscriber", skip(parameters, pool))]
-pub async fn confirm(parameters: web::Query<Paramerters>, pool: web::Data<PgPool>) -> HttpResponse {
-    let id = match get_subscriber_id_from_token(&pool, &parameters.subscription_token).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    match id {
-        //Non-exixting token
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-            HttpResponse::Ok().finish()
-        }
+//landing page of the link in the confirmation email; only the POST below changes anything,
+//so mail security scanners that prefetch every link cannot confirm on the reader's behalf
+#[tracing::instrument(name="Show confirmation page", skip(parameters, pool))]
+pub async fn confirm_form(parameters: web::Query<Paramerters>, pool: web::Data<PgPool>) -> Result<HttpResponse, ConfirmationError> {
+    let subscription_token = SubscriptionToken::parse(parameters.0.subscription_token)?;
+    let expires_at = get_token_expiry(&pool, &subscription_token)
+        .await
+        .context("Failed to look up the subscription token.")?
+        .ok_or(ConfirmationError::UnknownToken)?;
+    if expires_at <= Utc::now() {
+        return Err(ConfirmationError::LinkExpired);
     }
+    let action = htmlescape::encode_attribute(&format!(
+        "/subscriptions/confirm?subscription_token={}",
+        subscription_token.as_ref()
+    ));
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Confirm your subscription</title>
+</head>
+<body>
+    <p>Thanks for signing up to our newsletter!</p>
+    <form action="{action}" method="post">
+        <button type="submit">Confirm my subscription</button>
+    </form>
+</body>
+</html>"#,
+        )))
 }
 
-#[tracing::instrument(name="Mark subscriber as confirmed", skip(subscriber_id, pool))]
-pub async fn confirm_subscriber(pool:&PgPool, subscriber_id:Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query!(r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,subscriber_id).execute(pool)
+#[tracing::instrument(name="Confirm a pending subscriber", skip(parameters, pool, metrics))]
+pub async fn confirm(parameters: web::Query<Paramerters>, pool: web::Data<PgPool>, metrics: web::Data<Metrics>) -> Result<HttpResponse, ConfirmationError> {
+    let subscription_token = SubscriptionToken::parse(parameters.0.subscription_token)?;
+    let mut transaction = metrics
+        .begin_transaction(&pool)
         .await
-        .map_err(|e| {
-            tracing::error!("Failed to execute query: {:?}", e);
-            e
-        })?;
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    //Non-exixting or already used token
+    let token = consume_subscription_token(&mut transaction, &subscription_token)
+        .await
+        .context("Failed to consume the subscription token.")?
+        .ok_or(ConfirmationError::UnknownToken)?;
+    //returning early drops the transaction, so the expired token stays and the link keeps explaining itself
+    if token.expires_at <= Utc::now() {
+        return Err(ConfirmationError::LinkExpired);
+    }
+    confirm_subscriber(&mut transaction, token.subscriber_id)
+        .await
+        .context("Failed to mark the subscriber as confirmed.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the SQL transaction to confirm a subscriber.")?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscription confirmed</title>
+</head>
+<body>
+    <p>Your subscription is confirmed, welcome aboard!</p>
+</body>
+</html>"#,
+        ))
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmationError {
+    #[error(transparent)]
+    InvalidToken(#[from] SubscriptionTokenError),
+    #[error("There is no pending subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error("The confirmation link has expired.")]
+    LinkExpired,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ConfirmationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmationError::InvalidToken(_) => StatusCode::BAD_REQUEST,
+            ConfirmationError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmationError::LinkExpired => StatusCode::GONE,
+            ConfirmationError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ConfirmationError::LinkExpired => link_expired(),
+            _ => HttpResponse::build(self.status_code()).finish(),
+        }
+    }
+}
+
+fn link_expired() -> HttpResponse {
+    HttpResponse::Gone()
+        .content_type(ContentType::html())
+        .body(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Link expired</title>
+</head>
+<body>
+    <p>This confirmation link has expired. Subscribe again to get a new one.</p>
+</body>
+</html>"#,
+        )
+}
+
+//an old confirmation link must not undo an unsubscribe
+#[tracing::instrument(name="Mark subscriber as confirmed", skip(subscriber_id, transaction))]
+pub async fn confirm_subscriber(transaction: &mut Transaction<'_, Postgres>, subscriber_id:Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1 AND status = 'pending_confirmation'"#,subscriber_id).execute(transaction)
+        .await?;
     Ok(())
 }
 
+pub struct StoredToken {
+    pub subscriber_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+//deleting the token is what makes it single-use
+#[tracing::instrument(name="Consume a subscription token", skip(subscription_token, transaction))]
+pub async fn consume_subscription_token(transaction: &mut Transaction<'_, Postgres>, subscription_token: &SubscriptionToken) -> Result<Option<StoredToken>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        StoredToken,
+        r#"DELETE FROM subscriptions_tokens WHERE token_hash=$1 RETURNING subscriber_id, expires_at"#,
+        hash_subscription_token(subscription_token)
+    ).fetch_optional(transaction)
+        .await?;
+    Ok(result)
+}
 
-#[tracing::instrument(name="Get the subscriber_id from token", skip(subscription_token, pool))]
-pub async fn get_subscriber_id_from_token(pool: &PgPool, subscription_token: &str) -> Result<Option<Uuid>, sqlx::Error> {
+#[tracing::instrument(name="Look up a subscription token", skip(subscription_token, pool))]
+pub async fn get_token_expiry(pool: &PgPool, subscription_token: &SubscriptionToken) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
     let result = sqlx::query!(
-        r#"SELECT subscriber_id FROM subscriptions_tokens WHERE subscription_token=$1"#,
-        subscription_token
+        r#"SELECT expires_at FROM subscriptions_tokens WHERE token_hash=$1"#,
+        hash_subscription_token(subscription_token)
     ).fetch_optional(pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to execute query: {:?}", e);
-            e
-        })?;
-    Ok(result.map(|r| r.subscriber_id))
-}
\ No newline at end of file
+        .await?;
+    Ok(result.map(|r| r.expires_at))
+}
@@ -0,0 +1,125 @@
+use crate::utils::error_chain_fmt;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    token: String,
+}
+
+//landing page of the link in the email footer; only the POST below changes anything,
+//so link scanners and prefetchers cannot unsubscribe anyone by following it
+#[tracing::instrument(name = "Show unsubscribe page", skip(parameters, pool))]
+pub async fn unsubscribe_form(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !subscriber_exists(&pool, &parameters.token)
+        .await
+        .context("Failed to look up the subscriber.")?
+    {
+        return Err(UnsubscribeError::UnknownToken);
+    }
+    let action = htmlescape::encode_attribute(&format!(
+        "/subscriptions/unsubscribe?token={}",
+        parameters.token
+    ));
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>Do you want to stop receiving our newsletter?</p>
+    <form action="{action}" method="post">
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+//target of both the confirmation page and RFC 8058 one-click requests from mail clients
+#[tracing::instrument(name = "Unsubscribe a subscriber", skip(parameters, pool))]
+pub async fn unsubscribe(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if !mark_subscriber_as_unsubscribed(&pool, &parameters.token)
+        .await
+        .context("Failed to mark the subscriber as unsubscribed.")?
+    {
+        return Err(UnsubscribeError::UnknownToken);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribed</title>
+</head>
+<body>
+    <p>You have been unsubscribed. You will not receive any more issues.</p>
+</body>
+</html>"#,
+        ))
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::UnknownToken => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).finish()
+    }
+}
+
+#[tracing::instrument(name = "Look up subscriber by unsubscribe token", skip(pool, token))]
+async fn subscriber_exists(pool: &PgPool, token: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE unsubscribe_token = $1"#,
+        token
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+//unsubscribing twice is not an error, mail clients are free to retry the one-click POST
+#[tracing::instrument(name = "Mark subscriber as unsubscribed", skip(pool, token))]
+async fn mark_subscriber_as_unsubscribed(pool: &PgPool, token: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE unsubscribe_token = $1"#,
+        token
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
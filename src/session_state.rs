@@ -0,0 +1,51 @@
+use actix_session::{Session, SessionExt, SessionGetError, SessionInsertError};
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use std::future::{ready, Ready};
+use uuid::Uuid;
+
+//a typed facade over the session, so handlers cannot misspell keys or store the wrong types
+pub struct TypedSession(Session);
+
+impl TypedSession {
+    const USER_ID_KEY: &'static str = "user_id";
+    const FLASH_KEY: &'static str = "flash";
+
+    //a new session key on login prevents session fixation
+    pub fn renew(&self) {
+        self.0.renew();
+    }
+
+    pub fn insert_user_id(&self, user_id: Uuid) -> Result<(), SessionInsertError> {
+        self.0.insert(Self::USER_ID_KEY, user_id)
+    }
+
+    pub fn get_user_id(&self) -> Result<Option<Uuid>, SessionGetError> {
+        self.0.get(Self::USER_ID_KEY)
+    }
+
+    //drop the state and rotate the key, the old cookie becomes useless but a flash can still be shown
+    pub fn log_out(&self) {
+        self.0.clear();
+        self.0.renew();
+    }
+
+    //a one-off message shown by the next page that is rendered
+    pub fn insert_flash(&self, message: &str) -> Result<(), SessionInsertError> {
+        self.0.insert(Self::FLASH_KEY, message)
+    }
+
+    pub fn take_flash(&self) -> Option<String> {
+        self.0.remove_as::<String>(Self::FLASH_KEY).and_then(Result::ok)
+    }
+}
+
+impl FromRequest for TypedSession {
+    //return the same error returned by the implementation of `FromRequest` for `Session`
+    type Error = <Session as FromRequest>::Error;
+    type Future = Ready<Result<TypedSession, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(TypedSession(req.get_session())))
+    }
+}
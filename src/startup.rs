@@ -1,51 +1,191 @@
-use crate::email_client::EmailClient;
-use crate::routes::{check_health, subscribe};
-use crate::routes::subscriptions_confirm::confirm;
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::email_client::{EmailSender, MeteredEmailSender};
+use crate::email_outbox::dispatcher_loop;
+use crate::issue_delivery_worker::worker_loop;
+use crate::metrics::{record_http_metrics, Metrics};
+use crate::authentication::reject_anonymous_users;
+use crate::routes::{
+    check_health, health_live, health_ready, login, login_form, metrics, password_reset_form,
+    publish_newsletter, request_password_reset, reset_password, reset_password_form, subscribe,
+    unsubscribe, unsubscribe_form, HealthState, SubscribeSettings,
+};
+use crate::routes::subscriptions_confirm::{confirm, confirm_form};
+use crate::routes::admin::{
+    admin_dashboard, change_password, change_password_form, list_dead_letters, log_out,
+    requeue_dead_letters,
+};
+use crate::session_store::PostgresSessionStore;
+use actix_session::SessionMiddleware;
+use actix_web::cookie::Key;
+use actix_web::middleware::from_fn;
+use crate::configuration::{ApplicationSettings, DatabaseSettings, Settings};
 use sqlx::postgres::PgPoolOptions;
 use actix_web::dev::Server;
 use actix_web::{web, App, HttpServer};
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::future::Future;
+use std::sync::Arc;
 use secrecy::ExposeSecret;
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
 use tracing_actix_web::TracingLogger;
 
 
 pub struct Application {
     port: u16,
-    server: Server
+    server: Server,
+    db_pool: PgPool,
+    worker_email_client: Arc<dyn EmailSender>,
+    metrics: Arc<Metrics>,
+    base_url: String,
+    max_delivery_attempts: i16,
+    shutdown: CancellationToken,
 }
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
-        let sender_email = configuration
+        let app_metrics = Arc::new(Metrics::new().map_err(std::io::Error::other)?);
+        let provider = configuration.email_client.provider.as_str();
+        let email_client = configuration
+            .email_client
+            .clone()
+            .client()
+            .map_err(std::io::Error::other)?;
+        let email_client: Arc<dyn EmailSender> =
+            Arc::new(MeteredEmailSender::new(email_client, provider, app_metrics.clone()));
+        let max_delivery_attempts = configuration.email_client.max_attempts;
+        //the background workers get their own client so they do not share state with the http workers
+        let worker_email_client = configuration
             .email_client
-            .sender()
-            .expect("Invalid sender email address");
+            .client()
+            .map_err(std::io::Error::other)?;
+        let worker_email_client: Arc<dyn EmailSender> = Arc::new(MeteredEmailSender::new(
+            worker_email_client,
+            provider,
+            app_metrics.clone(),
+        ));
 
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout
-        );
         let address = format!("{}:{}", configuration.application.host, configuration.application.port);
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
-        let server = run(listener, connection_pool, email_client, configuration.application.base_url)?;
-        Ok(Self { port, server })
+        let server = run(
+            listener,
+            connection_pool.clone(),
+            email_client,
+            app_metrics.clone(),
+            &configuration.application,
+        )?;
+        Ok(Self {
+            port,
+            server,
+            db_pool: connection_pool,
+            worker_email_client,
+            metrics: app_metrics,
+            base_url: configuration.application.base_url,
+            max_delivery_attempts,
+            shutdown: CancellationToken::new(),
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    //cancelling the token has the same effect as SIGTERM
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    //serve http requests and drain the delivery queues until a shutdown signal or a failure;
+    //either way every task is told to stop, and the first failure is returned once they all have
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let shutdown = self.shutdown;
+        tokio::spawn(cancel_on_signal(shutdown.clone()));
+        //stop accepting connections and give in-flight requests the grace period to finish
+        let server_handle = self.server.handle();
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown.cancelled().await;
+                server_handle.stop(true).await;
+            }
+        });
+
+        let server = tokio::spawn(self.server);
+        let dispatcher = tokio::spawn(dispatcher_loop(
+            self.db_pool.clone(),
+            self.metrics.clone(),
+            self.worker_email_client.clone(),
+            self.max_delivery_attempts,
+            shutdown.clone(),
+        ));
+        let worker = tokio::spawn(worker_loop(
+            self.db_pool,
+            self.metrics,
+            self.worker_email_client,
+            self.base_url,
+            self.max_delivery_attempts,
+            shutdown.clone(),
+        ));
+        let (server, dispatcher, worker) = tokio::join!(
+            supervise("HTTP server", server, shutdown.clone()),
+            supervise("Email outbox dispatcher", async_ok(dispatcher), shutdown.clone()),
+            supervise("Background delivery worker", async_ok(worker), shutdown.clone()),
+        );
+        server.and(dispatcher).and(worker)
+    }
+}
+
+async fn cancel_on_signal(shutdown: CancellationToken) {
+    match shutdown_signal().await {
+        Ok(signal) => {
+            tracing::info!("Received {}, shutting down", signal);
+            shutdown.cancel();
+        }
+        Err(e) => tracing::error!("Failed to listen for shutdown signals: {}", e),
+    }
+}
+
+async fn shutdown_signal() -> Result<&'static str, std::io::Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            outcome = tokio::signal::ctrl_c() => outcome.map(|_| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.map(|_| "Ctrl-C")
     }
 }
 
+//the background loops return nothing, they can only fail by panicking
+async fn async_ok(task: JoinHandle<()>) -> Result<Result<(), std::io::Error>, JoinError> {
+    task.await.map(Ok)
+}
+
+//a task that stops before shutdown was requested is a failure and brings the others down with it
+async fn supervise(
+    task_name: &str,
+    task: impl Future<Output = Result<Result<(), std::io::Error>, JoinError>>,
+    shutdown: CancellationToken,
+) -> Result<(), std::io::Error> {
+    let outcome = task.await;
+    let stopped_early = !shutdown.is_cancelled();
+    shutdown.cancel();
+    let reason = match outcome {
+        Ok(Ok(())) if !stopped_early => return Ok(()),
+        Ok(Ok(())) => "exited".to_string(),
+        Ok(Err(e)) => e.to_string(),
+        Err(e) => e.to_string(),
+    };
+    tracing::error!("{} stopped: {}", task_name, reason);
+    Err(std::io::Error::other(format!("{} stopped: {}", task_name, reason)))
+}
+
 pub fn get_connection_pool(configuration: &DatabaseSettings)->PgPool {
     PgPoolOptions::new()
         .acquire_timeout(std::time::Duration::from_secs(2))
@@ -56,8 +196,9 @@ pub struct ApplicationBaseUrl(pub String);
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
+    email_client: Arc<dyn EmailSender>,
+    app_metrics: Arc<Metrics>,
+    settings: &ApplicationSettings,
 ) -> Result<Server, std::io::Error> {
     /*
     web::Data will wrap the reference of the connection variable in ARC.
@@ -66,22 +207,78 @@ pub fn run(
     */
     let db_pool = web::Data::new(db_pool);
     //move so that we are able to capture the connection variable into the closure
-    let email_client = web::Data::new(email_client);
-    let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let email_client = web::Data::from(email_client);
+    let app_metrics = web::Data::from(app_metrics);
+    let base_url = web::Data::new(ApplicationBaseUrl(settings.base_url.clone()));
+    let idempotency_retention = web::Data::new(settings.idempotency_retention());
+    let subscribe_settings = web::Data::new(SubscribeSettings {
+        base_url: ApplicationBaseUrl(settings.base_url.clone()),
+        retention: settings.idempotency_retention(),
+        resend_interval: settings.confirmation_resend_interval(),
+        token_ttl: settings.subscription_token_ttl(),
+    });
+    let health = web::Data::new(HealthState::new(
+        settings.readiness_timeout(),
+        settings.readiness_probe_email_provider,
+    ));
+    //signs the session cookie, so a client cannot forge a session key
+    let secret_key = Key::from(settings.hmac_secret.expose_secret().as_bytes());
+    let session_store = PostgresSessionStore::new(db_pool.get_ref().clone());
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(SessionMiddleware::new(session_store.clone(), secret_key.clone()))
             .wrap(TracingLogger::default())
-            //health check
+            //outermost, so the latency covers the session and logging middleware too
+            .wrap(from_fn(record_http_metrics))
+            //health check, kept for existing monitors
             .route("/health_check", web::get().to(check_health))
+            //liveness and readiness probes for the orchestrator
+            .route("/health/live", web::get().to(health_live))
+            .route("/health/ready", web::get().to(health_ready))
+            //Prometheus scrape target
+            .route("/metrics", web::get().to(metrics))
             //post requests to add subscriptions
             .route("/subscriptions", web::post().to(subscribe))
-            //get request to confirm subscriber
-            .route("/subscriptions/confirm", web::get().to(confirm))
+            //the emailed link opens a page whose button confirms the subscriber
+            .route("/subscriptions/confirm", web::get().to(confirm_form))
+            .route("/subscriptions/confirm", web::post().to(confirm))
+            //leaving the list, either through the page or a one-click POST from the mail client
+            .route("/subscriptions/unsubscribe", web::get().to(unsubscribe_form))
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            //publish a newsletter issue to all confirmed subscribers
+            .route("/newsletters", web::post().to(publish_newsletter))
+            //browser login for admins
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            //forgotten passwords are reset through a single-use emailed link
+            .route("/password_reset", web::get().to(password_reset_form))
+            .route("/password_reset", web::post().to(request_password_reset))
+            .route("/password_reset/confirm", web::get().to(reset_password_form))
+            .route("/password_reset/confirm", web::post().to(reset_password))
+            //everything under /admin requires a logged-in session
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(reject_anonymous_users))
+                    .route("/dashboard", web::get().to(admin_dashboard))
+                    .route("/logout", web::post().to(log_out))
+                    .route("/password", web::get().to(change_password_form))
+                    .route("/password", web::post().to(change_password))
+                    //inspect and retry deliveries that ran out of attempts
+                    .route("/dead_letters", web::get().to(list_dead_letters))
+                    .route("/dead_letters/requeue", web::post().to(requeue_dead_letters)),
+            )
             //register the db connection as part of the application state
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(idempotency_retention.clone())
+            .app_data(subscribe_settings.clone())
+            .app_data(health.clone())
+            .app_data(app_metrics.clone())
     })
+    //signals are handled by `Application::run_until_stopped`, which also stops the background tasks
+    .disable_signals()
+    .shutdown_timeout(settings.shutdown_grace_period().as_secs())
     .listen(listener)?
     .run();
     Ok(server)
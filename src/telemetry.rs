@@ -1,29 +1,363 @@
+use crate::configuration::{OtlpProtocol, OtlpSettings};
+use hmac::{Hmac, Mac};
+use opentelemetry::global;
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{ExporterBuildError, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::fmt;
 use std::io::Sink;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::RwLock;
+use tokio::task::JoinHandle;
 use tracing::Subscriber;
 //telemetry
 use tracing::subscriber::set_global_default;
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
 
+//spans are also exported through `tracer_provider` when there is one
 pub fn get_subscriber<W>(
     name: String,
     env_filter: String,
     sink: W,
+    tracer_provider: Option<&SdkTracerProvider>,
 ) -> impl Subscriber + Sync + Send
 where
     W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
+    let otel_layer = tracer_provider
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(name.clone())));
     let formatting_layer = BunyanFormattingLayer::new(name, sink);
     Registry::default()
         .with(env_filter)
+        .with(otel_layer)
         .with(JsonStorageLayer)
         .with(formatting_layer)
 }
 
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     LogTracer::init().expect("Failed to set logger"); //redirect all log events to subscriber
+    //W3C trace context, read from incoming requests by TracingLogger and written on outgoing ones
+    global::set_text_map_propagator(TraceContextPropagator::new());
     set_global_default(subscriber).expect("Failed to set subscriber")
 }
+
+//spans are batched on a background thread; call `shutdown` on the provider before exiting to flush them
+pub fn otlp_tracer_provider(
+    service_name: &str,
+    settings: &OtlpSettings,
+) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = match settings.protocol {
+        OtlpProtocol::Grpc => opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(settings.endpoint.clone())
+            .with_timeout(settings.timeout())
+            .build()?,
+        OtlpProtocol::Http => opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", settings.endpoint.trim_end_matches('/')))
+            .with_timeout(settings.timeout())
+            .build()?,
+    };
+    Ok(SdkTracerProvider::builder()
+        .with_resource(Resource::builder().with_service_name(service_name.to_string()).build())
+        .with_batch_exporter(exporter)
+        .build())
+}
+
+//`traceparent` (and `tracestate`) of the current span, so the provider's logs can be tied to our trace
+pub fn trace_context_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers))
+    });
+    headers
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+//run cpu-heavy work on the blocking pool without losing the span it was started from
+pub fn spawn_blocking_with_tracing<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let current_span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || current_span.in_scope(f))
+}
+
+//how subscriber emails and names show up in logs, spans and Debug output
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactionPolicy {
+    //a short keyed digest, so the events of one subscriber can still be matched without the address
+    Hash,
+    //first character and the email domain only, e.g. u***@example.com
+    #[default]
+    Mask,
+    //nothing but a placeholder
+    Drop,
+}
+
+//process wide like the subscriber itself; mask until the configuration says otherwise
+static REDACTION_POLICY: AtomicU8 = AtomicU8::new(RedactionPolicy::Mask as u8);
+
+//addresses are easy to guess, an unkeyed digest could be reversed by hashing a list of them
+static REDACTION_KEY: RwLock<Option<Hmac<Sha256>>> = RwLock::new(None);
+
+pub fn set_redaction_policy(
+    policy: RedactionPolicy,
+    key: Option<&Secret<String>>,
+) -> Result<(), String> {
+    if policy == RedactionPolicy::Hash && key.is_none() {
+        return Err("The hash redaction policy needs a `telemetry.redaction_key`".into());
+    }
+    *REDACTION_KEY.write().unwrap() = key.map(|key| redaction_key(key.expose_secret()));
+    REDACTION_POLICY.store(policy as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+fn redaction_key(key: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length")
+}
+
+pub fn redaction_policy() -> RedactionPolicy {
+    match REDACTION_POLICY.load(Ordering::Relaxed) {
+        p if p == RedactionPolicy::Hash as u8 => RedactionPolicy::Hash,
+        p if p == RedactionPolicy::Drop as u8 => RedactionPolicy::Drop,
+        _ => RedactionPolicy::Mask,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Pii {
+    Email,
+    Name,
+}
+
+//formats a personal value according to the redaction policy, e.g. `subscriber_email = %redact_email(&email)`
+pub struct Redacted<'a> {
+    value: &'a str,
+    kind: Pii,
+    policy: RedactionPolicy,
+    //only looked up for the hash policy
+    key: Option<Hmac<Sha256>>,
+}
+
+pub fn redact_email(email: &str) -> Redacted<'_> {
+    Redacted::new(email, Pii::Email)
+}
+
+pub fn redact_name(name: &str) -> Redacted<'_> {
+    Redacted::new(name, Pii::Name)
+}
+
+impl<'a> Redacted<'a> {
+    fn new(value: &'a str, kind: Pii) -> Self {
+        let policy = redaction_policy();
+        let key = match policy {
+            RedactionPolicy::Hash => REDACTION_KEY.read().unwrap().clone(),
+            _ => None,
+        };
+        Self {
+            value,
+            kind,
+            policy,
+            key,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_policy(self, policy: RedactionPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    #[cfg(test)]
+    fn with_key(self, key: &str) -> Self {
+        Self {
+            key: Some(redaction_key(key)),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.policy {
+            RedactionPolicy::Hash => match &self.key {
+                Some(key) => {
+                    let mut mac = key.clone();
+                    mac.update(self.value.as_bytes());
+                    let digest = hex::encode(mac.finalize().into_bytes());
+                    write!(f, "hmac:{}", &digest[..16])
+                }
+                //`set_redaction_policy` refuses hash without a key, never fall back to a plain digest
+                None => write!(f, "[redacted]"),
+            },
+            RedactionPolicy::Mask => {
+                let first = self.value.chars().next().map(String::from).unwrap_or_default();
+                match (self.kind, self.value.rsplit_once('@')) {
+                    (Pii::Email, Some((_, domain))) => write!(f, "{}***@{}", first, domain),
+                    _ => write!(f, "{}***", first),
+                }
+            }
+            RedactionPolicy::Drop => write!(f, "[redacted]"),
+        }
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_subscriber, otlp_tracer_provider, redact_email, redact_name, set_redaction_policy,
+        trace_context_headers, RedactionPolicy,
+    };
+    use crate::configuration::{OtlpProtocol, OtlpSettings};
+    use claims::assert_err;
+    use opentelemetry::global;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn spans_are_exported_to_the_collector() {
+        //arrange
+        let collector = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/traces"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1..)
+            .mount(&collector)
+            .await;
+        let settings = OtlpSettings {
+            endpoint: collector.uri(),
+            protocol: OtlpProtocol::Http,
+            timeout_milliseconds: None,
+        };
+        let provider = otlp_tracer_provider("test", &settings).unwrap();
+        let subscriber = get_subscriber("test".into(), "info".into(), std::io::sink, Some(&provider));
+
+        //act
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("Store subscription token").in_scope(|| {});
+        });
+        //the exporter's blocking http client must not hold up the runtime serving the collector
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        //assert
+        let requests = collector.received_requests().await.unwrap();
+        let span_name = b"Store subscription token";
+        assert!(requests
+            .iter()
+            .any(|r| r.body.windows(span_name.len()).any(|w| w == span_name)));
+    }
+
+    #[test]
+    fn the_current_span_is_injected_as_a_traceparent_header() {
+        //arrange
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = get_subscriber("test".into(), "info".into(), std::io::sink, Some(&provider));
+
+        //act
+        let headers = tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("Send a confirmation email").in_scope(trace_context_headers)
+        });
+
+        //assert
+        let traceparent = headers["traceparent"].to_str().unwrap();
+        let parts: Vec<&str> = traceparent.split('-').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "00");
+        assert_eq!(parts[1].len(), 32);
+        assert_eq!(parts[2].len(), 16);
+    }
+
+    #[test]
+    fn nothing_is_injected_outside_of_a_trace() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        assert!(trace_context_headers().is_empty());
+    }
+
+    #[test]
+    fn emails_are_masked_down_to_their_first_character_and_domain() {
+        let email = redact_email("ursula_le_guin@gmail.com").with_policy(RedactionPolicy::Mask);
+        assert_eq!(email.to_string(), "u***@gmail.com");
+    }
+
+    #[test]
+    fn names_are_masked_down_to_their_first_character() {
+        let name = redact_name("Ursula Le Guin").with_policy(RedactionPolicy::Mask);
+        assert_eq!(name.to_string(), "U***");
+        let name = redact_name("").with_policy(RedactionPolicy::Mask);
+        assert_eq!(name.to_string(), "***");
+    }
+
+    #[test]
+    fn hashing_gives_the_same_digest_for_the_same_value() {
+        let hash = |email| {
+            redact_email(email)
+                .with_policy(RedactionPolicy::Hash)
+                .with_key("redaction-key")
+                .to_string()
+        };
+        let first = hash("ursula@example.com");
+        assert_eq!(first, hash("ursula@example.com"));
+        assert_ne!(first, hash("le_guin@example.com"));
+        assert!(!first.contains("ursula"));
+    }
+
+    #[test]
+    fn the_digest_depends_on_the_redaction_key() {
+        let email = "ursula@example.com";
+        let first = redact_email(email)
+            .with_policy(RedactionPolicy::Hash)
+            .with_key("redaction-key");
+        let second = redact_email(email)
+            .with_policy(RedactionPolicy::Hash)
+            .with_key("another-redaction-key");
+        assert_ne!(first.to_string(), second.to_string());
+    }
+
+    #[test]
+    fn hashing_without_a_key_is_refused() {
+        assert_err!(set_redaction_policy(RedactionPolicy::Hash, None));
+    }
+
+    #[test]
+    fn dropped_values_leave_only_a_placeholder() {
+        let email = redact_email("ursula@example.com").with_policy(RedactionPolicy::Drop);
+        assert_eq!(email.to_string(), "[redacted]");
+    }
+}
@@ -1,4 +1,6 @@
 use crate::helpers::spawn_app;
+use sqlx::{Connection, PgConnection};
+use z2p::configuration::get_configuration;
 #[tokio::test]
 async fn health_check_works() {
     //spawn app and get address
@@ -16,3 +18,76 @@ async fn health_check_works() {
     assert_eq!(Some(0), response.content_length());
 }
 
+
+#[tokio::test]
+async fn liveness_reports_version_and_uptime() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    let response = reqwest::get(&format!("{}/health/live", &app.address))
+        .await
+        .expect("Failed to execute request!");
+
+    //assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert!(body["uptime_seconds"].is_u64());
+}
+
+#[tokio::test]
+async fn readiness_checks_the_database() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    let response = reqwest::get(&format!("{}/health/ready", &app.address))
+        .await
+        .expect("Failed to execute request!");
+
+    //assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["checks"]["database"]["status"], "up");
+    assert!(body["checks"]["database"]["latency_ms"].is_u64());
+    //the email provider is only probed when asked to
+    assert!(body["checks"].get("email_provider").is_none());
+}
+
+#[tokio::test]
+async fn readiness_returns_a_503_when_the_database_is_unreachable() {
+    //arrange
+    let app = spawn_app().await;
+    //refuse new connections and drop the ones the application already holds
+    let database_name: String = sqlx::query_scalar("SELECT current_database()")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let configuration = get_configuration().expect("Failed to read configuration");
+    let mut maintenance = PgConnection::connect_with(&configuration.database.without_db())
+        .await
+        .unwrap();
+    sqlx::query(&format!(r#"ALTER DATABASE "{}" ALLOW_CONNECTIONS false"#, database_name))
+        .execute(&mut maintenance)
+        .await
+        .unwrap();
+    sqlx::query("SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE datname = $1")
+        .bind(&database_name)
+        .execute(&mut maintenance)
+        .await
+        .unwrap();
+
+    //act
+    let response = reqwest::get(&format!("{}/health/ready", &app.address))
+        .await
+        .expect("Failed to execute request!");
+
+    //assert
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "down");
+    assert_eq!(body["checks"]["database"]["status"], "down");
+}
@@ -1,24 +1,32 @@
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use std::net::TcpListener;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
-use z2p::configuration::{get_configuration, DatabaseSettings};
-use z2p::email_client::EmailClient;
+use z2p::configuration::{get_configuration, DatabaseSettings, EmailProvider};
+use z2p::email_client::EmailSender;
+use z2p::email_outbox::try_dispatch_email;
+use z2p::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use z2p::metrics::Metrics;
 use z2p::startup::{get_connection_pool, Application};
 use z2p::telemetry::{get_subscriber, init_subscriber};
-use wiremock::MockServer;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
 
 //ensure that the tracing stack is initialized only once using once_cell
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
     let subscriber_name = "test".to_string();
-    let mut subscriber;
+    //the writers have different types, so each branch builds and installs its own subscriber
     if std::env::var("TEST_LOG").is_ok() {
-        subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
+        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout, None);
         init_subscriber(subscriber);
     } else {
-        // subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::Sink);
-        subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
+        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink, None);
         init_subscriber(subscriber);
     };
 });
@@ -28,11 +36,68 @@ pub struct ConfirmationLinks {
     pub plain_text: reqwest::Url
 }
 
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+    pub email: String
+}
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+            email: format!("{}@example.com", Uuid::new_v4())
+        }
+    }
+
+    pub async fn login(&self, app: &TestApp) {
+        app.post_login(&serde_json::json!({
+            "username": &self.username,
+            "password": &self.password
+        }))
+        .await;
+    }
+
+    async fn store(&self, pool: &PgPool) {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        //match the parameters of the dummy hash used for unknown usernames
+        let password_hash = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(15000, 2, 1, None).unwrap(),
+        )
+        .hash_password(self.password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash, email) VALUES ($1, $2, $3, $4)",
+            self.user_id,
+            self.username,
+            password_hash,
+            self.email,
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
+}
+
 pub struct TestApp {
     pub address: String,
     pub db_pool: PgPool,
     pub email_server: MockServer,
-    pub port: u16
+    pub port: u16,
+    pub email_client: Arc<dyn EmailSender>,
+    //recorded by the delivery and outbox logic run from the tests, apart from the application's own
+    pub metrics: Metrics,
+    pub max_delivery_attempts: i16,
+    pub test_user: TestUser,
+    pub api_client: reqwest::Client,
+    //cancel to shut the application down as SIGTERM would, then await `application_task`
+    pub shutdown: CancellationToken,
+    pub application_task: JoinHandle<Result<(), std::io::Error>>,
 }
 impl TestApp {
     pub async fn post_subscriptions(&self, body:String) -> reqwest::Response {
@@ -45,6 +110,256 @@ impl TestApp {
             .expect("failed to execute request")
     }
 
+    //how the SPA and the mobile app subscribe
+    pub async fn post_subscriptions_json(&self, body: &serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(&format!("{}/subscriptions", &self.address))
+            .header("Accept", "application/json")
+            .json(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    //run the delivery worker logic until no queued task is due anymore
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            let pending = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue WHERE execute_after <= now()"#)
+                .fetch_one(&self.db_pool)
+                .await
+                .unwrap()
+                .count;
+            if pending == 0 {
+                break;
+            }
+            //the background worker might be holding the lock on the remaining tasks
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(&self.db_pool, &self.metrics, self.email_client.as_ref(), &self.address, self.max_delivery_attempts).await.unwrap() {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        }
+    }
+
+    //run the outbox dispatcher logic until no transactional email is due anymore
+    pub async fn dispatch_all_outbox_emails(&self) {
+        loop {
+            let pending = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM email_outbox WHERE failed_at IS NULL AND execute_after <= now()"#)
+                .fetch_one(&self.db_pool)
+                .await
+                .unwrap()
+                .count;
+            if pending == 0 {
+                break;
+            }
+            //the background dispatcher might be holding the lock on the remaining emails
+            if let ExecutionOutcome::EmptyQueue = try_dispatch_email(&self.db_pool, &self.metrics, self.email_client.as_ref(), self.max_delivery_attempts).await.unwrap() {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        }
+    }
+
+    //skip the backoff delay of every rescheduled outbox email
+    pub async fn fast_forward_outbox_retries(&self) {
+        sqlx::query!("UPDATE email_outbox SET execute_after = now()")
+            .execute(&self.db_pool)
+            .await
+            .unwrap();
+    }
+
+    //skip the backoff delay of every rescheduled delivery
+    pub async fn fast_forward_delivery_retries(&self) {
+        sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+            .execute(&self.db_pool)
+            .await
+            .unwrap();
+    }
+
+    pub async fn get_dead_letters(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dead_letters", &self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn post_requeue_dead_letters(&self, body: serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/dead_letters/requeue", &self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", &self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn get_change_password(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/password", &self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn get_change_password_html(&self) -> String {
+        self.get_change_password().await.text().await.unwrap()
+    }
+
+    pub async fn post_change_password<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/password", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn post_password_reset_request(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/password_reset", &self.address))
+            .form(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn get_password_reset_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/password_reset", &self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_reset_password<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/password_reset/confirm", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&body)
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    //pretend every stored idempotency key is older than the retention window
+    pub async fn expire_idempotency_keys(&self) {
+        sqlx::query!("UPDATE idempotency SET created_at = now() - interval '1 year'")
+            .execute(&self.db_pool)
+            .await
+            .unwrap();
+    }
+
+    pub async fn get_metrics(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/metrics", &self.address))
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    pub async fn get_unsubscribe(&self, token: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/subscriptions/unsubscribe", &self.address))
+            .query(&[("token", token)])
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    //what the button on the confirmation page does
+    pub async fn post_confirmation(&self, confirmation_link: &reqwest::Url) -> reqwest::Response {
+        self.api_client
+            .post(confirmation_link.clone())
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
+    //mimics the RFC 8058 request a mail client sends for one-click unsubscribe
+    pub async fn post_unsubscribe(&self, token: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/subscriptions/unsubscribe", &self.address))
+            .query(&[("token", token)])
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("List-Unsubscribe=One-Click")
+            .send()
+            .await
+            .expect("failed to execute request")
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
         //extract the link from one of the request fields
@@ -75,8 +390,6 @@ impl TestApp {
 }
 pub async fn spawn_app() -> TestApp {
     Lazy::force(&TRACING);
-    let subscriber = get_subscriber("test".into(), "debug".into(), std::io::stdout);
-    init_subscriber(subscriber);
 
     let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind to random port");
     let port = listener.local_addr().unwrap().port();
@@ -88,20 +401,37 @@ pub async fn spawn_app() -> TestApp {
         let mut c = get_configuration().expect("Failed to read configuration");
         c.database.database_name = Uuid::new_v4().to_string(); //modify the db name to random string
         c.application.port = 0;
+        c.email_client.provider = EmailProvider::Postmark; //talk to the mock server instead of the local sink
         c.email_client.base_url = email_server.uri(); //use mockserver as uri
         c
     };
 
+    configure_database(&configuration.database).await;
     let application = Application::build(configuration.clone()).await.expect("Failed to bind address");
     let application_port = application.port();
     let address = format!("http://localhost:{}", application_port);
-    let _ = tokio::spawn(application.run_until_stopped()); //task to spawn an async function. in this case - the server
-    TestApp {
+    let shutdown = application.shutdown_token();
+    let application_task = tokio::spawn(application.run_until_stopped()); //task to spawn an async function. in this case - the server
+    let test_app = TestApp {
         address,
         db_pool: get_connection_pool(&configuration.database),
         email_server,
-        port: application_port
-    }
+        port: application_port,
+        max_delivery_attempts: configuration.email_client.max_attempts,
+        email_client: configuration.email_client.client().unwrap(),
+        metrics: Metrics::new().unwrap(),
+        test_user: TestUser::generate(),
+        //keep the session cookie between requests and let tests inspect redirects
+        api_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .cookie_store(true)
+            .build()
+            .unwrap(),
+        shutdown,
+        application_task,
+    };
+    test_app.test_user.store(&test_app.db_pool).await;
+    test_app
     //yet to add code to rollback
 }
 //we want this so that we are able to create dummy databases to run tests
@@ -116,8 +446,8 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
         .execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
         .await
         .expect("Failed to create database");
-    //migrate db --------------------------------------------------------------------this is causing error----------------------
-    let connection_pool = PgPool::connect_with(config.without_db().clone())
+    //migrate db
+    let connection_pool = PgPool::connect_with(config.with_db())
         .await
         .expect("Failed to connect to postgres");
     sqlx::migrate!("./migrations")
@@ -126,4 +456,55 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
         .expect("Failed to migrate the database");
 
     connection_pool //return this connection pool inside the spawn app function
-}
\ No newline at end of file
+}
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
+
+//use the public api of the application under test to create an unconfirmed subscriber
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_outbox_emails().await;
+
+    //inspect the requests received by the mock server to retrieve the confirmation link
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    let confirmation_link = create_unconfirmed_subscriber(app).await;
+    app.post_confirmation(&confirmation_link.html)
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+pub fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
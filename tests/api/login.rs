@@ -0,0 +1,59 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app};
+
+#[tokio::test]
+async fn an_error_flash_message_is_set_on_failure() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act - part 1 - try to login
+    let login_body = serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    });
+    let response = app.post_login(&login_body).await;
+
+    //assert
+    assert_is_redirect_to(&response, "/login");
+
+    //act - part 2 - follow the redirect
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains(r#"<p><i>Authentication failed</i></p>"#));
+
+    //act - part 3 - reload the login page
+    let html_page = app.get_login_html().await;
+    assert!(!html_page.contains(r#"<p><i>Authentication failed</i></p>"#));
+}
+
+#[tokio::test]
+async fn redirect_to_admin_dashboard_after_login_success() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act - part 1 - login
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+    let response = app.post_login(&login_body).await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+
+    //act - part 2 - follow the redirect
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+}
+
+#[tokio::test]
+async fn sessions_are_stored_in_postgres() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    app.test_user.login(&app).await;
+
+    //assert
+    let sessions = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM sessions WHERE expires_at > now()"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(sessions.count, 1);
+}
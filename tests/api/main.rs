@@ -1,4 +1,12 @@
 mod helpers;
+mod admin_dashboard;
+mod change_password;
 mod health_check;
+mod login;
+mod metrics;
+mod newsletters;
+mod password_reset;
+mod shutdown;
 mod subscriptions;
-mod subscriptions_confirm;
\ No newline at end of file
+mod subscriptions_confirm;
+mod subscriptions_unsubscribe;
@@ -0,0 +1,58 @@
+use crate::helpers::spawn_app;
+
+#[tokio::test]
+async fn metrics_are_exposed_in_prometheus_text_format() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    let response = app.get_metrics().await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "text/plain; version=0.0.4"
+    );
+    let text = response.text().await.unwrap();
+    assert!(text.contains("db_pool_connections "));
+    assert!(text.contains("db_pool_idle_connections "));
+    assert!(text.contains("db_pool_acquire_wait_seconds_count "));
+}
+
+#[tokio::test]
+async fn requests_are_counted_per_route_and_status() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    app.post_subscriptions("name=le%20guin".into()).await;
+    app.post_subscriptions("email=ursula_le_guin%40gmail.com".into()).await;
+    let text = app.get_metrics().await.text().await.unwrap();
+
+    //assert
+    assert!(text.contains(
+        r#"http_requests_total{method="POST",route="/subscriptions",status="400"} 2"#
+    ));
+    assert!(text.contains(
+        r#"http_request_duration_seconds_count{method="POST",route="/subscriptions"} 2"#
+    ));
+}
+
+#[tokio::test]
+async fn unknown_paths_share_a_single_route_label() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    app.api_client
+        .get(format!("{}/definitely/not/a/route", &app.address))
+        .send()
+        .await
+        .expect("failed to execute request");
+    let text = app.get_metrics().await.text().await.unwrap();
+
+    //assert
+    assert!(text.contains(r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#));
+    assert!(!text.contains("definitely"));
+}
@@ -0,0 +1,478 @@
+use crate::helpers::{
+    assert_is_redirect_to, create_confirmed_subscriber, create_unconfirmed_subscriber,
+    newsletter_request_body, spawn_app,
+};
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    //arrange
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 202);
+    //mock verifies on drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 202);
+    //mock verifies on drop that we have sent the newsletter email
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    //arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            "missing content",
+        ),
+    ];
+
+    for (invalid_body, error_message) in test_cases {
+        //act
+        let response = app.post_newsletters(invalid_body).await;
+
+        //assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}.",
+            error_message
+        );
+    }
+}
+
+#[tokio::test]
+async fn publishing_enqueues_one_delivery_per_confirmed_subscriber() {
+    //arrange
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status, unsubscribe_token)
+        VALUES ($1, 'confirmed_subscriber@gmail.com', 'confirmed', now(), 'confirmed', $2)
+        "#,
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4().simple().to_string()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    //act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 202);
+    let receipt: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(receipt["enqueued"], 1);
+}
+
+#[tokio::test]
+async fn a_failed_delivery_stays_in_the_queue_until_it_succeeds() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    //the first delivery fails, the retry succeeds
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+    let rescheduled = sqlx::query!("SELECT n_retries FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The failed delivery was removed from the queue");
+    app.fast_forward_delivery_retries().await;
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 202);
+    assert_eq!(rescheduled.n_retries, 1);
+    //mocks verify on drop that the failed delivery was retried exactly once
+}
+
+#[tokio::test]
+async fn a_delivery_is_dead_lettered_once_it_runs_out_of_attempts() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(app.max_delivery_attempts as u64)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    app.post_newsletters(newsletter_request_body()).await;
+    for _ in 0..app.max_delivery_attempts {
+        app.dispatch_all_pending_emails().await;
+        app.fast_forward_delivery_retries().await;
+    }
+
+    //assert
+    app.test_user.login(&app).await;
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    let dead_letters = dead_letters.as_array().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0]["subscriber_email"], "ursula_le_guin@gmail.com");
+    assert_eq!(dead_letters[0]["n_attempts"], app.max_delivery_attempts);
+}
+
+#[tokio::test]
+async fn client_errors_are_dead_lettered_without_retrying() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    app.test_user.login(&app).await;
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    assert_eq!(dead_letters[0]["n_attempts"], 1);
+}
+
+#[tokio::test]
+async fn requeued_dead_letters_are_delivered_again() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let receipt: serde_json::Value = app
+        .post_newsletters(newsletter_request_body())
+        .await
+        .json()
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    //act
+    app.test_user.login(&app).await;
+    let response = app
+        .post_requeue_dead_letters(serde_json::json!({
+            "newsletter_issue_id": receipt["newsletter_issue_id"],
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 200);
+    let requeue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(requeue["requeued"], 1);
+    let dead_letters: serde_json::Value = app.get_dead_letters().await.json().await.unwrap();
+    assert!(dead_letters.as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn requests_missing_authorization_are_rejected() {
+    //arrange
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    //assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn non_existing_user_is_rejected() {
+    //arrange
+    let app = spawn_app().await;
+    //random credentials
+    let username = Uuid::new_v4().to_string();
+    let password = Uuid::new_v4().to_string();
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    //assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn invalid_password_is_rejected() {
+    //arrange
+    let app = spawn_app().await;
+    let username = &app.test_user.username;
+    //random password
+    let password = Uuid::new_v4().to_string();
+    assert_ne!(app.test_user.password, password);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    //assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_dead_letters() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    let response = app.get_dead_letters().await;
+
+    //assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    //act - part 1 - submit the newsletter
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let first_receipt: serde_json::Value = response.json().await.unwrap();
+
+    //act - part 2 - submit it again with the same key
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let second_receipt: serde_json::Value = response.json().await.unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    assert_eq!(first_receipt, second_receipt);
+    let issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 1);
+    //mock verifies on drop that we have sent the newsletter email once
+}
+
+#[tokio::test]
+async fn reusing_an_idempotency_key_for_another_issue_is_rejected() {
+    //arrange
+    let app = spawn_app().await;
+    let idempotency_key = Uuid::new_v4().to_string();
+    let mut other_issue = newsletter_request_body();
+    other_issue["title"] = "Another newsletter title".into();
+
+    //act
+    let first = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+    let second = app
+        .post_newsletters_with_idempotency_key(other_issue, &idempotency_key)
+        .await;
+
+    //assert
+    assert_eq!(first.status().as_u16(), 202);
+    assert_eq!(second.status().as_u16(), 422);
+    let issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 1);
+}
+
+#[tokio::test]
+async fn the_idempotency_key_can_be_sent_in_the_body() {
+    //arrange
+    let app = spawn_app().await;
+    let mut body = newsletter_request_body();
+    body["idempotency_key"] = Uuid::new_v4().to_string().into();
+
+    //act
+    let first = app.post_newsletters(body.clone()).await;
+    let second = app.post_newsletters(body).await;
+
+    //assert
+    assert_eq!(first.status().as_u16(), 202);
+    assert_eq!(second.status().as_u16(), 202);
+    let issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 1);
+}
+
+#[tokio::test]
+async fn concurrent_form_submission_is_handled_gracefully() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let idempotency_key = Uuid::new_v4().to_string();
+
+    //act - submit the same newsletter twice at once
+    let response1 =
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key);
+    let response2 =
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key);
+    let (response1, response2) = tokio::join!(response1, response2);
+    app.dispatch_all_pending_emails().await;
+
+    //assert - one request does the work, the other either replays it or is told to retry
+    let statuses = [response1.status().as_u16(), response2.status().as_u16()];
+    assert!(statuses.contains(&202));
+    assert!(statuses.iter().all(|s| *s == 202 || *s == 409));
+    //mock verifies on drop that we have sent the newsletter email once
+}
+
+#[tokio::test]
+async fn an_expired_idempotency_key_is_processed_again() {
+    //arrange
+    let app = spawn_app().await;
+    let idempotency_key = Uuid::new_v4().to_string();
+    app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await
+        .error_for_status()
+        .unwrap();
+    app.expire_idempotency_keys().await;
+
+    //act
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key)
+        .await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 202);
+    let issues = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(issues.count, 2);
+}
+
+#[tokio::test]
+async fn an_invalid_idempotency_key_is_rejected() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body(), &"a".repeat(60))
+        .await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 400);
+}
@@ -0,0 +1,176 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const NEUTRAL_MESSAGE: &str = "<p><i>If an account is registered with that address, you will receive a link to reset your password.</i></p>";
+
+//request a reset for the test user and return the token carried by the emailed link
+async fn request_reset_token(app: &TestApp) -> String {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_password_reset_request(&app.test_user.email).await;
+    app.dispatch_all_outbox_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap().pop().unwrap();
+    let reset_link = app.get_confirmation_links(email_request).html;
+    assert_eq!(reset_link.path(), "/password_reset/confirm");
+    reset_link
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .map(|(_, token)| token.into_owned())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn unknown_addresses_get_the_same_answer_and_no_email() {
+    //arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    let response = app.post_password_reset_request("nobody@example.com").await;
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    assert_is_redirect_to(&response, "/password_reset");
+    assert!(app.get_password_reset_html().await.contains(NEUTRAL_MESSAGE));
+}
+
+#[tokio::test]
+async fn a_reset_link_is_emailed_to_known_addresses() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    request_reset_token(&app).await;
+
+    //assert
+    assert!(app.get_password_reset_html().await.contains(NEUTRAL_MESSAGE));
+}
+
+#[tokio::test]
+async fn the_reset_email_is_sent_by_the_outbox_not_the_request() {
+    //arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    //act - part 1 - the request answers without talking to the email provider
+    let response = app.post_password_reset_request(&app.test_user.email).await;
+    assert_is_redirect_to(&response, "/password_reset");
+    assert!(app.email_server.received_requests().await.unwrap().is_empty());
+
+    //act - part 2 - the dispatcher sends it
+    app.dispatch_all_outbox_emails().await;
+}
+
+#[tokio::test]
+async fn reset_tokens_are_stored_hashed() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    let token = request_reset_token(&app).await;
+
+    //assert
+    let stored = sqlx::query!("SELECT token_hash FROM password_reset_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_ne!(stored.token_hash, token);
+}
+
+#[tokio::test]
+async fn the_emailed_link_resets_the_password_only_once() {
+    //arrange
+    let app = spawn_app().await;
+    let token = request_reset_token(&app).await;
+    let new_password = Uuid::new_v4().to_string();
+    let body = serde_json::json!({
+        "token": &token,
+        "new_password": &new_password,
+        "new_password_check": &new_password,
+    });
+
+    //act - part 1 - reset the password
+    let response = app.post_reset_password(&body).await;
+    assert_is_redirect_to(&response, "/login");
+
+    //act - part 2 - login with the new password
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &new_password
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+
+    //act - part 3 - replay the link
+    let response = app.post_reset_password(&body).await;
+    assert_is_redirect_to(&response, "/password_reset");
+}
+
+#[tokio::test]
+async fn a_mismatched_password_check_keeps_the_token_in_the_retry_link() {
+    //arrange
+    let app = spawn_app().await;
+    //would split the query, cut it short or break the header if it was not encoded
+    let token = "a&b=c#d\r\ne";
+
+    //act
+    let response = app
+        .post_reset_password(&serde_json::json!({
+            "token": token,
+            "new_password": "a-long-enough-password",
+            "new_password_check": "another-long-password",
+        }))
+        .await;
+
+    //assert
+    assert_is_redirect_to(&response, "/password_reset/confirm?token=a%26b%3Dc%23d%0D%0Ae");
+    let location = response.headers().get("Location").unwrap().to_str().unwrap();
+    let retry_link = reqwest::Url::parse(&format!("{}{}", app.address, location)).unwrap();
+    let (_, round_tripped) = retry_link.query_pairs().find(|(key, _)| key == "token").unwrap();
+    assert_eq!(round_tripped, token);
+}
+
+#[tokio::test]
+async fn expired_links_are_rejected() {
+    //arrange
+    let app = spawn_app().await;
+    let token = request_reset_token(&app).await;
+    sqlx::query!("UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let new_password = Uuid::new_v4().to_string();
+
+    //act
+    let response = app
+        .post_reset_password(&serde_json::json!({
+            "token": &token,
+            "new_password": &new_password,
+            "new_password_check": &new_password,
+        }))
+        .await;
+
+    //assert
+    assert_is_redirect_to(&response, "/password_reset");
+    assert!(app
+        .get_password_reset_html()
+        .await
+        .contains("This password reset link is invalid or has expired."));
+}
@@ -0,0 +1,95 @@
+use crate::helpers::{newsletter_request_body, spawn_app};
+use std::time::Duration;
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn shutting_down_stops_the_server_and_the_background_workers() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    app.shutdown.cancel();
+    let outcome = tokio::time::timeout(Duration::from_secs(10), app.application_task)
+        .await
+        .expect("The application did not stop in time")
+        .unwrap();
+
+    //assert
+    assert!(outcome.is_ok());
+    let response = reqwest::Client::new()
+        .get(format!("{}/health_check", &app.address))
+        .send()
+        .await;
+    assert!(response.is_err());
+}
+
+#[tokio::test]
+async fn requests_in_flight_are_finished_before_shutting_down() {
+    //arrange
+    let app = spawn_app().await;
+    //a publish with this key waits on the row lock until the transaction below is rolled back
+    let idempotency_key = Uuid::new_v4().to_string();
+    let mut lock = app.db_pool.begin().await.unwrap();
+    sqlx::query!(
+        "INSERT INTO idempotency (user_id, idempotency_key, created_at) VALUES ($1, $2, now())",
+        app.test_user.user_id,
+        idempotency_key
+    )
+    .execute(&mut lock)
+    .await
+    .unwrap();
+
+    //act - shut down while the publish is blocked
+    let (response, _) = tokio::join!(
+        app.post_newsletters_with_idempotency_key(newsletter_request_body(), &idempotency_key),
+        async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            app.shutdown.cancel();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            lock.rollback().await.unwrap();
+        }
+    );
+
+    //assert
+    assert_eq!(response.status().as_u16(), 202);
+    let outcome = tokio::time::timeout(Duration::from_secs(10), app.application_task)
+        .await
+        .expect("The application did not stop in time")
+        .unwrap();
+    assert!(outcome.is_ok());
+}
+
+#[tokio::test]
+async fn emails_being_sent_are_finished_before_shutting_down() {
+    //arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(1)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_password_reset_request(&app.test_user.email).await;
+
+    //act - shut down once the dispatcher has started sending, the provider takes a second to answer
+    while app.email_server.received_requests().await.unwrap().is_empty() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    app.shutdown.cancel();
+
+    //assert
+    let outcome = tokio::time::timeout(Duration::from_secs(10), app.application_task)
+        .await
+        .expect("The application did not stop in time")
+        .unwrap();
+    assert!(outcome.is_ok());
+    //the send was answered and the email left the outbox, it was not abandoned half-way
+    let remaining = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM email_outbox"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(remaining, 0);
+}
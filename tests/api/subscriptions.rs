@@ -23,6 +23,39 @@ async fn subscribe_returns_a_400_on_for_invalid_fields() {
         )
     }
 }
+#[tokio::test]
+async fn subscribe_reports_every_invalid_field_as_problem_json() {
+    //arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("name=%3Cursula%3E&email=not-an-email".to_string(), vec![("name", "name.forbidden_characters"), ("email", "email.invalid")]),
+        (format!("name={}&email=ursula_le_guin%40gmail.com", "a".repeat(257)), vec![("name", "name.too_long")]),
+        (format!("name=%3C{}&email=ursula_le_guin%40gmail.com", "a".repeat(256)), vec![("name", "name.too_long"), ("name", "name.forbidden_characters")]),
+        ("name=Ursula&email=".to_string(), vec![("email", "email.invalid")]),
+    ];
+    for (body, expected) in test_cases {
+        //act
+        let response = app.post_subscriptions(body.clone()).await;
+
+        //assert
+        assert_eq!(400, response.status().as_u16());
+        assert_eq!(response.headers()["Content-Type"], "application/problem+json");
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["status"], 400);
+        let errors: Vec<(String, String)> = problem["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["field"].as_str().unwrap().to_owned(), e["code"].as_str().unwrap().to_owned()))
+            .collect();
+        let expected: Vec<(String, String)> = expected
+            .into_iter()
+            .map(|(field, code)| (field.to_owned(), code.to_owned()))
+            .collect();
+        assert_eq!(errors, expected, "Unexpected errors for {}", body);
+    }
+}
+
 #[tokio::test]
 async fn subscribe_returns_a_200_for_valid_form_data() {
     //arrange
@@ -100,6 +133,7 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data() {
 
     //act
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
     //assert
     //mock asserts on drop
 }
@@ -118,6 +152,7 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     //act
     app.post_subscriptions((body.into())).await;
+    app.dispatch_all_outbox_emails().await;
 
     //assert
     //get the first intercepted request
@@ -125,4 +160,362 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
     let confirmation_links = app.get_confirmation_links(&email_request);
     // the 2 links should be identical
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn subscribe_is_idempotent() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&idempotency_key=signup-form-1";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    let first = app.post_subscriptions(body.to_string()).await;
+    let second = app.post_subscriptions(body.to_string()).await;
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+    //mock verifies on drop that a single confirmation email went out
+}
+
+#[tokio::test]
+async fn reusing_an_idempotency_key_for_another_subscriber_is_rejected() {
+    //arrange
+    let app = spawn_app().await;
+    let first_body = "name=le%20guin&email=ursula_le_guin%40gmail.com&idempotency_key=signup-form-1";
+    let second_body = "name=octavia&email=octavia_butler%40gmail.com&idempotency_key=signup-form-1";
+
+    //act
+    let first = app.post_subscriptions(first_body.to_string()).await;
+    let second = app.post_subscriptions(second_body.to_string()).await;
+
+    //assert - the second visitor is told, rather than handed the first visitor's answer
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(422, second.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribe_succeeds_while_the_email_provider_is_down() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    let response = app.post_subscriptions(body.to_string()).await;
+
+    //assert
+    assert_eq!(200, response.status().as_u16());
+    let outbox = sqlx::query!("SELECT recipient, subject FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.recipient, "ursula_le_guin@gmail.com");
+    assert_eq!(outbox.subject, "Welcome!");
+}
+
+#[tokio::test]
+async fn a_failed_confirmation_email_is_retried_until_it_is_sent() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.to_string())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    //act
+    app.dispatch_all_outbox_emails().await;
+    app.fast_forward_outbox_retries().await;
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    let remaining = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM email_outbox"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, 0);
+    //mocks verify on drop that the email was sent on the second attempt
+}
+
+#[tokio::test]
+async fn a_confirmation_email_that_runs_out_of_attempts_is_kept_as_failed() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(app.max_delivery_attempts as u64)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.to_string())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    //act
+    for _ in 0..app.max_delivery_attempts {
+        app.dispatch_all_outbox_emails().await;
+        app.fast_forward_outbox_retries().await;
+    }
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    let failed = sqlx::query!(
+        r#"SELECT n_retries, last_error, failed_at IS NOT NULL AS "failed!" FROM email_outbox"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert!(failed.failed);
+    assert_eq!(failed.n_retries, app.max_delivery_attempts);
+    assert!(failed.last_error.is_some());
+}
+
+#[tokio::test]
+async fn no_confirmation_email_is_queued_if_the_subscriber_cannot_be_saved() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    //sabotage the database
+    sqlx::query!("ALTER TABLE subscriptions_tokens DROP COLUMN token_hash;")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    //act
+    let response = app.post_subscriptions(body.to_string()).await;
+
+    //assert
+    assert_eq!(500, response.status().as_u16());
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM email_outbox"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+}
+
+#[tokio::test]
+async fn subscribing_again_while_pending_resends_the_confirmation_email() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.to_string()).await;
+    app.dispatch_all_outbox_emails().await;
+    //pretend the resend interval has passed
+    sqlx::query!("UPDATE subscriptions SET confirmation_sent_at = now() - interval '1 day'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    //act
+    let response = app.post_subscriptions(body.to_string()).await;
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    assert_eq!(200, response.status().as_u16());
+    let requests = app.email_server.received_requests().await.unwrap();
+    let first_link = app.get_confirmation_links(&requests[0]).html;
+    let second_link = app.get_confirmation_links(&requests[1]).html;
+    assert_ne!(first_link, second_link);
+    //only the latest link is still valid
+    assert_eq!(app.post_confirmation(&first_link).await.status().as_u16(), 401);
+    assert_eq!(app.post_confirmation(&second_link).await.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn confirmation_emails_are_not_resent_within_the_resend_interval() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    let first = app.post_subscriptions(body.to_string()).await;
+    let second = app.post_subscriptions(body.to_string()).await;
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+    //mock verifies on drop that only one email went out
+}
+
+#[tokio::test]
+async fn subscribing_with_a_confirmed_email_returns_a_200_without_sending_an_email() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.to_string()).await;
+    app.dispatch_all_outbox_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    app.post_confirmation(&confirmation_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+    sqlx::query!("UPDATE subscriptions SET confirmation_sent_at = now() - interval '1 day'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    //act
+    let response = app.post_subscriptions(body.to_string()).await;
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    //mock verifies on drop that no second email went out
+}
+
+#[tokio::test]
+async fn subscribe_accepts_json_and_answers_in_json() {
+    //arrange
+    let app = spawn_app().await;
+    let body = serde_json::json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com"
+    });
+
+    //act
+    let response = app.post_subscriptions_json(&body).await;
+
+    //assert
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(response.headers()["Content-Type"], "application/json");
+    let receipt: serde_json::Value = response.json().await.unwrap();
+    assert!(receipt["message"].is_string());
+    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribe_answers_in_json_only_when_json_is_the_preferred_type() {
+    //arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("application/json;q=0", false, "json refused with q=0"),
+        ("application/json;q=0, */*", false, "json refused next to a wildcard"),
+        ("text/html, application/json;q=0.5", false, "html preferred"),
+        ("text/html;q=0.5, application/json", true, "json preferred"),
+        ("application/*", true, "any application type"),
+        ("*/*", true, "any type"),
+    ];
+    for (i, (accept, json, desc)) in test_cases.into_iter().enumerate() {
+        let body = serde_json::json!({
+            "name": "le guin",
+            "email": format!("ursula_le_guin_{}@gmail.com", i)
+        });
+
+        //act
+        let response = reqwest::Client::new()
+            .post(&format!("{}/subscriptions", &app.address))
+            .header("Accept", accept)
+            .json(&body)
+            .send()
+            .await
+            .expect("failed to execute request");
+
+        //assert
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(
+            json,
+            response.headers().get("Content-Type").is_some_and(|v| v == "application/json"),
+            "The response format was wrong when {}",
+            desc
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_validates_json_bodies_like_forms() {
+    //arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (serde_json::json!({"name": "le guin"}), "missing the email"),
+        (serde_json::json!({"name": "", "email": "not-an-email"}), "invalid fields"),
+        (serde_json::json!("le guin"), "not an object"),
+    ];
+    for (body, desc) in test_cases {
+        //act
+        let response = app.post_subscriptions_json(&body).await;
+
+        //assert
+        assert_eq!(400, response.status().as_u16(), "The api did not fail with 400 when the payload had {}", desc);
+    }
+}
+
+#[tokio::test]
+async fn subscribe_rejects_unsupported_content_types() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    let response = reqwest::Client::new()
+        .post(&format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "text/plain")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .unwrap();
+
+    //assert
+    assert_eq!(415, response.status().as_u16());
+}
@@ -13,6 +13,30 @@ async fn confirmations_without_token_are_rejected_with_a_400() {
     assert_eq!(response.status().as_u16(), 400);
 }
 
+#[tokio::test]
+async fn malformed_tokens_are_rejected_with_a_400() {
+    //arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("", "empty token"),
+        ("tooshort", "too short"),
+        ("aaaaaaaaaaaaaaaaaaaaaaaaaa", "too long"),
+        ("aaaaaaaaaaaa'aaaaaaaaaaaa", "not alphanumeric"),
+    ];
+    for (token, desc) in test_cases {
+        let link = Url::parse_with_params(
+            &format!("{}/subscriptions/confirm", app.address),
+            &[("subscription_token", token)],
+        ).unwrap();
+        //act
+        let page = reqwest::get(link.clone()).await.unwrap();
+        let confirmation = app.post_confirmation(&link).await;
+        //assert
+        assert_eq!(page.status().as_u16(), 400, "The page did not return a 400 for a token that is {}.", desc);
+        assert_eq!(confirmation.status().as_u16(), 400, "The confirmation did not return a 400 for a token that is {}.", desc);
+    }
+}
+
 #[tokio::test]
 async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
     //arrange
@@ -26,6 +50,7 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
     let confirmation_links = app.get_confirmation_links(&email_request);
     //act
@@ -34,6 +59,39 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
     assert_eq!(response.status().as_u16(), 200);
 }
 
+#[tokio::test]
+async fn opening_the_confirmation_link_does_not_confirm_the_subscriber() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20gun&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(&email_request);
+
+    //act - a link scanner following the link
+    let response = reqwest::get(confirmation_links.html.clone()).await.unwrap();
+
+    //assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(r#"method="post""#));
+    assert!(html_page.contains("Confirm my subscription"));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await.unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+    //the link still works once the reader presses the button
+    app.post_confirmation(&confirmation_links.html)
+        .await
+        .error_for_status().unwrap();
+}
+
 #[tokio::test]
 async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     //arrange
@@ -45,12 +103,13 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .mount(&app.email_server)
         .await;
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
     let confirmation_links = app.get_confirmation_links(&email_request);
 
     //act
-    reqwest::get(confirmation_links.html)
-        .await.unwrap()
+    app.post_confirmation(&confirmation_links.html)
+        .await
         .error_for_status().unwrap();
     //assert
     let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
@@ -59,4 +118,89 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     assert_eq!(saved.email, "ursula_le_guin@gmail.com");
     assert_eq!(saved.name, "le guin");
     assert_eq!(saved.status, "confirmed");
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn a_confirmation_link_can_only_be_used_once() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20gun&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    app.post_confirmation(&confirmation_links.html)
+        .await
+        .error_for_status().unwrap();
+
+    //act
+    let response = app.post_confirmation(&confirmation_links.html).await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn an_expired_confirmation_link_is_rejected_with_a_410() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20gun&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    sqlx::query!("UPDATE subscriptions_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    //act
+    let response = app.post_confirmation(&confirmation_links.html).await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 410);
+    assert!(response.text().await.unwrap().contains("Subscribe again to get a new one"));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await.unwrap();
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscription_tokens_are_not_stored_in_plaintext() {
+    //arrange
+    let app = spawn_app().await;
+    let body = "name=le%20gun&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(&email_request);
+    let token = confirmation_links.html.query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+    let stored = sqlx::query!("SELECT token_hash, expires_at > issued_at AS \"expires_later!\" FROM subscriptions_tokens")
+        .fetch_one(&app.db_pool)
+        .await.unwrap();
+    assert_ne!(stored.token_hash, token);
+    assert!(stored.expires_later);
+}
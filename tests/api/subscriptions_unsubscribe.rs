@@ -0,0 +1,203 @@
+use crate::helpers::{create_confirmed_subscriber, newsletter_request_body, spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn unsubscribe_token(app: &TestApp) -> String {
+    sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .unsubscribe_token
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+#[tokio::test]
+async fn newsletter_emails_carry_one_click_unsubscribe_headers() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let expected_link = format!(
+        "<{}/subscriptions/unsubscribe?token={}>",
+        app.address,
+        unsubscribe_token(&app).await
+    );
+    assert_eq!(body["Headers"][0]["Name"], "List-Unsubscribe");
+    assert_eq!(body["Headers"][0]["Value"], expected_link.as_str());
+    assert_eq!(body["Headers"][1]["Name"], "List-Unsubscribe-Post");
+    assert_eq!(body["Headers"][1]["Value"], "List-Unsubscribe=One-Click");
+}
+
+#[tokio::test]
+async fn confirmation_emails_do_not_carry_unsubscribe_headers() {
+    //arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_outbox_emails().await;
+
+    //assert
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body.get("Headers").is_none());
+}
+
+#[tokio::test]
+async fn the_unsubscribe_page_does_not_change_the_subscription() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app).await;
+
+    //act
+    let response = app.get_unsubscribe(&token).await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(r#"method="post""#));
+    assert!(html_page.contains(&token));
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_stops_further_deliveries() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    //act
+    let response = app.post_unsubscribe(&token).await;
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "unsubscribed");
+    //mock verifies on drop that the newsletter was not sent
+}
+
+#[tokio::test]
+async fn queued_deliveries_are_skipped_after_unsubscribing() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    //act
+    app.post_unsubscribe(&token).await.error_for_status().unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    //assert
+    let queued = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+    //mock verifies on drop that the newsletter was not sent
+}
+
+#[tokio::test]
+async fn unsubscribing_twice_succeeds() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let token = unsubscribe_token(&app).await;
+
+    //act
+    let first = app.post_unsubscribe(&token).await;
+    let second = app.post_unsubscribe(&token).await;
+
+    //assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn unknown_unsubscribe_tokens_are_rejected() {
+    //arrange
+    let app = spawn_app().await;
+
+    //act
+    let page = app.get_unsubscribe("not-a-real-token").await;
+    let one_click = app.post_unsubscribe("not-a-real-token").await;
+
+    //assert
+    assert_eq!(page.status().as_u16(), 401);
+    assert_eq!(one_click.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn an_old_confirmation_link_does_not_resubscribe() {
+    //arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    app.post_unsubscribe(&unsubscribe_token(&app).await)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    //act
+    app.post_confirmation(&confirmation_links.html).await;
+
+    //assert
+    assert_eq!(subscriber_status(&app).await, "unsubscribed");
+}